@@ -0,0 +1,226 @@
+use std::{fs, path::PathBuf, time::{Duration, UNIX_EPOCH}};
+use anyhow::{bail, Context, Result};
+use term_ansi::*;
+
+use crate::{
+    bluetooth::{normalize_mac, print_devices, BtDeviceInfo, DeviceMap, SyncOptions, DEFAULT_LINK_KEY_TYPE},
+    keyfile::KeyFile,
+    regfile::{hex_bytes, hex_upper, le_number},
+    store::{map_adapters, PairingSink, PairingSource},
+    utils::write_atomic,
+};
+
+/// `DevType`: which transports the device was bonded over.
+const DEV_TYPE_BREDR: u8 = 1;
+const DEV_TYPE_BLE: u8 = 2;
+const DEV_TYPE_DUAL: u8 = 3;
+
+/// `LE_KEY_PENC` is the LTK, Rand, EDIV, security level and key size.
+const PENC_LEN: usize = 28;
+/// `LE_KEY_PID` is the IRK, identity address type and identity address.
+const PID_LEN: usize = 23;
+
+/// The security level in `LE_KEY_PENC`, Fluoride's `SMP_SEC_UNAUTHENTICATE` and `SMP_SEC_AUTHENTICATED`.
+const SEC_LEVEL_UNAUTHENTICATED: u8 = 1;
+const SEC_LEVEL_AUTHENTICATED: u8 = 4;
+
+/// BlueZ writes class and appearance in hex, Android in decimal.
+fn hex_to_decimal(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+fn is_mac(section: &str) -> bool {
+    let parts: Vec<&str> = section.split(':').collect();
+    parts.len() == 6 && parts.iter().all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The device section Fluoride writes for a pairing. Keys are stored in the byte order
+/// BlueZ uses, only in lower case; the key Android distributed itself (`LE_KEY_LENC`) is
+/// derived from its own root key and cannot be carried over.
+fn device_section(info: &BtDeviceInfo) -> String {
+    let mut entries: Vec<(&str, String)> = Vec::new();
+    if info.name != info.mac {
+        entries.push(("Name", info.name.clone()));
+    }
+    if let Some(secs) = info.last_written.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        entries.push(("Timestamp", secs.as_secs().to_string()));
+    }
+    if let Some(class) = hex_to_decimal(&info.class) {
+        entries.push(("DevClass", class.to_string()));
+    }
+    if let Some(appearance) = hex_to_decimal(&info.appearance) {
+        entries.push(("Appearance", appearance.to_string()));
+    }
+
+    let dev_type = match (info.link_key.is_empty(), info.ltk.is_empty()) {
+        (false, false) => DEV_TYPE_DUAL,
+        (true, false) => DEV_TYPE_BLE,
+        _ => DEV_TYPE_BREDR,
+    };
+    entries.push(("DevType", dev_type.to_string()));
+    let addr_type = u8::from(info.address_type != "public");
+    if !info.ltk.is_empty() {
+        entries.push(("AddrType", addr_type.to_string()));
+    }
+
+    if !info.link_key.is_empty() {
+        let link_key_type = if info.link_key_type.is_empty() { DEFAULT_LINK_KEY_TYPE } else { &info.link_key_type };
+        entries.push(("LinkKeyType", link_key_type.to_string()));
+        entries.push(("PinLength", "0".to_string()));
+        entries.push(("LinkKey", info.link_key.to_lowercase()));
+    }
+
+    if !info.ltk.is_empty() {
+        let mut penc = hex_bytes(&info.ltk);
+        penc.extend(info.erand.parse::<u64>().unwrap_or(0).to_le_bytes());
+        penc.extend(info.ediv.parse::<u16>().unwrap_or(0).to_le_bytes());
+        penc.push(if info.authenticated == Some(true) { SEC_LEVEL_AUTHENTICATED } else { SEC_LEVEL_UNAUTHENTICATED });
+        penc.push(info.enc_size.parse().unwrap_or(16));
+        entries.push(("LE_KEY_PENC", hex_upper(&penc).to_lowercase()));
+    }
+    if !info.irk.is_empty() {
+        let mut pid = hex_bytes(&info.irk);
+        pid.push(addr_type);
+        pid.extend(hex_bytes(&info.mac.replace(':', "")));
+        entries.push(("LE_KEY_PID", hex_upper(&pid).to_lowercase()));
+    }
+
+    if let Some([source, vendor, product, version]) = info.device_id() {
+        entries.push(("SdpDiVendorIdSource", source.to_string()));
+        entries.push(("SdpDiManufacturer", vendor.to_string()));
+        entries.push(("SdpDiModel", product.to_string()));
+        entries.push(("SdpDiHardwareVersion", version.to_string()));
+    }
+
+    let mut section = format!("[{}]\n", info.mac.to_lowercase());
+    for (key, value) in entries {
+        section.push_str(&format!("{} = {}\n", key, value));
+    }
+    section
+}
+
+/// A `bt_config.conf` fragment with the `[Adapter]` section and one section per device,
+/// to be merged into the file on the phone. All devices must belong to one adapter.
+pub fn android_config(bt_device_info: &DeviceMap) -> Result<String> {
+    let mut adapters: Vec<&str> = bt_device_info.values().map(|info| info.adapter.as_str()).collect();
+    adapters.sort();
+    adapters.dedup();
+    if adapters.len() > 1 {
+        bail!("bt_config.conf holds a single adapter, but the pairings are on {}; map them to one with --adapter-map", adapters.join(", "));
+    }
+
+    let mut devices: Vec<&BtDeviceInfo> = bt_device_info.values().collect();
+    devices.sort_by(|a, b| a.mac.cmp(&b.mac));
+
+    let mut content = String::from("[Adapter]\n");
+    if let Some(adapter) = adapters.first().filter(|adapter| !adapter.is_empty()) {
+        content.push_str(&format!("Address = {}\n", adapter.to_lowercase()));
+    }
+    if let Some(local_irk) = devices.iter().map(|info| &info.local_irk).find(|irk| !irk.is_empty()) {
+        content.push_str(&format!("LE_LOCAL_KEY_IRK = {}\n", local_irk.to_lowercase()));
+    }
+    for info in devices {
+        content.push('\n');
+        content.push_str(&device_section(info));
+    }
+    Ok(content)
+}
+
+fn read_device(config: &KeyFile, section: &str, adapter: &str, local_irk: &str) -> BtDeviceInfo {
+    let get = |key: &str| config.get(section, key).unwrap_or_default().trim();
+    let number = |key: &str| get(key).parse::<u64>().ok();
+
+    let mut info = BtDeviceInfo {
+        adapter: adapter.to_string(),
+        mac: normalize_mac(section),
+        local_irk: local_irk.to_string(),
+        address_type: if number("AddrType").unwrap_or(0) == 0 { "public" } else { "static" }.to_string(),
+        link_key: get("LinkKey").to_uppercase(),
+        link_key_type: get("LinkKeyType").to_string(),
+        last_written: number("Timestamp").map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        class: number("DevClass").map(|class| format!("0x{:06x}", class)).unwrap_or_default(),
+        appearance: number("Appearance").map(|appearance| format!("0x{:04x}", appearance)).unwrap_or_default(),
+        ..Default::default()
+    };
+
+    if let Some(vendor) = number("SdpDiManufacturer") {
+        info.vendor = vendor.to_string();
+        info.device_id_source = number("SdpDiVendorIdSource").unwrap_or(1).to_string();
+        info.product = number("SdpDiModel").unwrap_or(0).to_string();
+        info.version = number("SdpDiHardwareVersion").unwrap_or(0).to_string();
+    }
+
+    let penc = hex_bytes(get("LE_KEY_PENC"));
+    if penc.len() == PENC_LEN {
+        info.ltk = hex_upper(&penc[..16]);
+        info.erand = le_number(&penc[16..24]).to_string();
+        info.ediv = le_number(&penc[24..26]).to_string();
+        info.authenticated = Some(penc[26] & SEC_LEVEL_AUTHENTICATED != 0);
+        info.enc_size = penc[27].to_string();
+    } else if !penc.is_empty() {
+        eprintln!("{}", yellow!("Warning: LE_KEY_PENC of {} is {} bytes instead of {}, skipping it", section, penc.len(), PENC_LEN));
+    }
+
+    let pid = hex_bytes(get("LE_KEY_PID"));
+    if pid.len() == PID_LEN {
+        info.irk = hex_upper(&pid[..16]);
+    }
+    if info.ltk.is_empty() {
+        info.address_type = "public".to_string();
+    }
+    info
+}
+
+/// Reads the pairings of a `bt_config.conf`, named after the device or its address.
+pub fn parse_android_config(content: &str) -> DeviceMap {
+    let config = KeyFile::parse(content);
+    let adapter = config.get("Adapter", "Address").map(normalize_mac).unwrap_or_default();
+    let local_irk = config.get("Adapter", "LE_LOCAL_KEY_IRK").unwrap_or_default().to_uppercase();
+
+    let mut bt_device_info = DeviceMap::new();
+    for section in config.groups().filter(|section| is_mac(section)) {
+        let mut info = read_device(&config, section, &adapter, &local_irk);
+        if info.ltk.is_empty() && info.link_key.is_empty() {
+            continue;
+        }
+        info.name = config.get(section, "Name").map(str::trim).filter(|name| !name.is_empty()).map_or(info.mac.clone(), str::to_string);
+        bt_device_info.entry(info.key()).or_insert(info);
+    }
+    bt_device_info
+}
+
+/// Android's `bt_config.conf`: read as a source, written as a fragment to merge on the phone.
+pub struct AndroidConfig {
+    pub path: PathBuf,
+}
+
+impl PairingSource for AndroidConfig {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let content = fs::read_to_string(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let mut bt_device_info = parse_android_config(&content);
+
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        print_devices("Android", &self.path.display().to_string(), &bt_device_info);
+
+        Ok(bt_device_info)
+    }
+}
+
+impl PairingSink for AndroidConfig {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+        let bt_device_info = map_adapters(bt_device_info, &[], options);
+        write_atomic(&self.path, android_config(&bt_device_info)?)?;
+        println!("{}", green!("\n=== {} device(s) written to {}, merge it into bt_config.conf ===", bt_device_info.len(), self.path.display()));
+        Ok(true)
+    }
+}
@@ -1,67 +1,520 @@
-use std::{collections::HashMap, fs, path::{Path, PathBuf}, process::Command};
+use std::{collections::HashMap, fs, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, process::Command, sync::OnceLock, time::SystemTime};
 
 use anyhow::Result;
-use regex::Regex;
+use serde::{Deserialize, Serialize};
 use term_ansi::*;
 
-use crate::partitions::*;
+use crate::{bluez::LinuxSource, keyfile::KeyFile, partitions::*, store::{open_sink, open_source, sync_pairings, StoreSpec}, regfile::{encode_reg, migrate_adapter_reg, windows_update_reg}, utils::{format_timestamp, rename_durable, write_atomic}};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BtDeviceInfo {
+    /// Device name, or the address when none is known.
+    pub name: String,
+    pub adapter: String,
     pub mac: String,
     pub ltk: String,
     pub erand: String,
-    pub ediv: String
+    pub ediv: String,
+    pub irk: String,
+    pub address_type: String,
+    pub enc_size: String,
+    /// BR/EDR link key, set for classic and dual-mode devices.
+    pub link_key: String,
+    /// BlueZ `[LinkKey] Type`, e.g. 5 for an authenticated combination key. Empty
+    /// when the source does not record it.
+    pub link_key_type: String,
+    /// Whether the LE pairing was MITM-protected, when the source records it.
+    pub authenticated: Option<bool>,
+    /// Key the local adapter distributed in a legacy pairing, used when the device is central.
+    pub peripheral_ltk: String,
+    pub peripheral_erand: String,
+    pub peripheral_ediv: String,
+    /// Identity resolving key of the local adapter the device is paired with.
+    pub local_irk: String,
+    /// When the pairing was last written, if the source records it.
+    pub last_written: Option<SystemTime>,
+    /// Partition or file the pairing was read from.
+    #[serde(skip)]
+    pub source: String,
+    /// Class of device, as BlueZ writes it (`0x240404`).
+    pub class: String,
+    /// LE GAP appearance, as BlueZ writes it (`0x03c2`).
+    pub appearance: String,
+    /// `[DeviceID]` fields, in decimal. The source is 1 for Bluetooth SIG, 2 for USB IF.
+    pub device_id_source: String,
+    pub vendor: String,
+    pub product: String,
+    pub version: String,
 }
 
-pub fn get_ltk(c: &str) -> String {
-    let mut in_ltk = false;
-    for line in c.lines() {
-        if line.trim() == "[LongTermKey]" {
-            in_ltk = true;
-            continue;
+/// Pairings by adapter and device address, so a device paired with two adapters keeps both.
+pub type DeviceMap = HashMap<(String, String), BtDeviceInfo>;
+
+/// Builds a device map; a later pairing with the same adapter and address replaces an earlier one.
+pub fn device_map(devices: impl IntoIterator<Item = BtDeviceInfo>) -> DeviceMap {
+    devices.into_iter().map(|info| (info.key(), info)).collect()
+}
+
+/// A pairing named `name`, the one on the lowest adapter address if there are several.
+pub fn device_named<'a>(bt_device_info: &'a DeviceMap, name: &str) -> Option<&'a BtDeviceInfo> {
+    bt_device_info.values()
+        .filter(|info| info.name == name)
+        .min_by(|a, b| (&a.adapter, &a.mac).cmp(&(&b.adapter, &b.mac)))
+}
+
+/// `[LinkKey] Type` for sources that do not record it: an unauthenticated SSP combination key.
+pub const DEFAULT_LINK_KEY_TYPE: &str = "4";
+
+/// How the LE keys were created, which decides the sections BlueZ expects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairingType {
+    /// LE legacy pairing: the LTK comes with EDIV/Rand and each side distributes its own key.
+    Legacy,
+    /// LE Secure Connections: one LTK for both roles, EDIV and Rand are zero.
+    SecureConnections,
+}
+
+impl BtDeviceInfo {
+    /// The adapter and device address the device map is keyed by.
+    pub fn key(&self) -> (String, String) {
+        (self.adapter.clone(), self.mac.clone())
+    }
+
+    /// The key shown in reports: the LTK, or the link key for classic-only devices.
+    pub fn display_key(&self) -> &str {
+        if self.ltk.is_empty() { &self.link_key } else { &self.ltk }
+    }
+
+    /// Whether both hold the same pairing: the LTK, link key and IRK all match.
+    pub fn same_keys(&self, other: &BtDeviceInfo) -> bool {
+        self.ltk.eq_ignore_ascii_case(&other.ltk)
+            && self.link_key.eq_ignore_ascii_case(&other.link_key)
+            && self.irk.eq_ignore_ascii_case(&other.irk)
+    }
+
+    /// `SupportedTechnologies` entries for the transports this device has keys for.
+    pub fn technologies(&self) -> Vec<&'static str> {
+        let mut technologies = Vec::new();
+        if !self.link_key.is_empty() {
+            technologies.push("BR/EDR");
+        }
+        if !self.ltk.is_empty() {
+            technologies.push("LE");
         }
-        if in_ltk && line.starts_with("Key=") {
-            return line[4..].to_string();
+        technologies
+    }
+
+    /// `[DeviceID]` Source, Vendor, Product and Version, with BlueZ's defaults for the
+    /// ones missing, or `None` when the vendor is unknown.
+    pub fn device_id(&self) -> Option<[&str; 4]> {
+        fn or<'a>(value: &'a str, default: &'static str) -> &'a str {
+            if value.is_empty() { default } else { value }
         }
+        (!self.vendor.is_empty()).then(|| [or(&self.device_id_source, "1"), self.vendor.as_str(), or(&self.product, "0"), or(&self.version, "0")])
+    }
+
+    pub fn pairing_type(&self) -> PairingType {
+        let zero = |value: &str| value.is_empty() || value == "0";
+        if zero(&self.ediv) && zero(&self.erand) {
+            PairingType::SecureConnections
+        } else {
+            PairingType::Legacy
+        }
+    }
+}
+
+/// Name of the section holding the key the local adapter distributed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PeripheralKeySection {
+    /// Whichever the installed bluetoothd writes, asked only when a file needs it.
+    #[default]
+    Installed,
+    /// `[PeripheralLongTermKey]`, written by BlueZ 5.62 and later.
+    Peripheral,
+    /// `[SlaveLongTermKey]`, written by older BlueZ.
+    Slave,
+}
+
+impl PeripheralKeySection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeripheralKeySection::Installed => PeripheralKeySection::detect().name(),
+            PeripheralKeySection::Peripheral => "PeripheralLongTermKey",
+            PeripheralKeySection::Slave => "SlaveLongTermKey",
+        }
+    }
+
+    /// Picks the section name from `bluetoothd --version` output such as `5.55`.
+    pub fn for_version(version: &str) -> Option<PeripheralKeySection> {
+        let (major, minor) = version.trim().split_once('.')?;
+        let version = (major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?);
+        Some(if version >= (5, 62) { PeripheralKeySection::Peripheral } else { PeripheralKeySection::Slave })
+    }
+
+    /// Asks the installed bluetoothd, which distributions keep outside of PATH, once per run.
+    pub fn detect() -> PeripheralKeySection {
+        static DETECTED: OnceLock<PeripheralKeySection> = OnceLock::new();
+        *DETECTED.get_or_init(|| {
+            ["bluetoothd", "/usr/libexec/bluetooth/bluetoothd", "/usr/lib/bluetooth/bluetoothd", "/usr/sbin/bluetoothd"]
+                .iter()
+                .filter_map(|cmd| Command::new(cmd).arg("--version").output().ok())
+                .filter(|output| output.status.success())
+                .find_map(|output| PeripheralKeySection::for_version(&String::from_utf8_lossy(&output.stdout)))
+                .unwrap_or(PeripheralKeySection::Peripheral)
+        })
+    }
+}
+
+/// What to do when a device directory is renamed to a MAC that already has one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CollisionPolicy {
+    /// Write the new keys into the existing directory and fold the old one into it.
+    #[default]
+    Merge,
+    /// Leave both directories untouched.
+    Skip,
+    /// Delete the existing directory and rename over it.
+    Replace,
+}
+
+/// Which side a sync copies pairings from.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SyncDirection {
+    /// Windows keys always replace the Linux ones.
+    #[default]
+    WindowsToLinux,
+    /// Per device, whichever side was paired last wins.
+    Auto,
+}
+
+/// The side `--direction auto` picked for one device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncSource {
+    Windows,
+    Linux,
+    /// Both sides already hold the same keys.
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncDecision {
+    pub name: String,
+    pub source: SyncSource,
+    pub reason: String,
+    pub windows: BtDeviceInfo,
+    /// The Linux pairing, with the Windows adapter so it can be written back.
+    pub linux: BtDeviceInfo,
+}
+
+/// How a device's pairing compares between Windows and Linux.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceState {
+    InSync,
+    KeyDiffers,
+    AddressDiffers,
+    WindowsOnly,
+    LinuxOnly,
+}
+
+impl DeviceState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DeviceState::InSync => "in sync",
+            DeviceState::KeyDiffers => "key differs",
+            DeviceState::AddressDiffers => "address differs",
+            DeviceState::WindowsOnly => "Windows-only",
+            DeviceState::LinuxOnly => "Linux-only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceStatus {
+    pub name: String,
+    /// Linux adapter directory, or the Windows adapter for devices without one.
+    pub adapter: String,
+    /// Linux address when the device is paired there, Windows address otherwise.
+    pub mac: String,
+    pub state: DeviceState,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncOptions {
+    pub import: bool,
+    /// Set by `export`, the only command that may read the running system's own BlueZ state.
+    pub export: bool,
+    pub direction: SyncDirection,
+    /// Where to write registry changes for devices where Linux holds the newer pairing.
+    pub reg: Option<PathBuf>,
+    /// Windows adapter address -> Linux adapter directory name.
+    pub adapter_map: HashMap<String, String>,
+    pub on_collision: CollisionPolicy,
+    /// Used when an info file has neither peripheral key section yet.
+    pub peripheral_section: PeripheralKeySection,
+    /// Read every Windows install instead of the first, resolving conflicts this way.
+    pub aggregate: Option<SourcePolicy>,
+    /// Take pairings from another Linux installation instead of Windows.
+    pub from_linux: Option<LinuxSource>,
+    /// Read pairings from this store instead of Windows.
+    pub source: Option<StoreSpec>,
+    /// Write pairings to this store instead of the running system's BlueZ state.
+    pub sink: Option<StoreSpec>,
+}
+
+/// One device directory `update_devices` changed, or left alone on a collision.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdateResult {
+    pub name: String,
+    pub old_mac: String,
+    pub new_mac: String,
+    pub old_ltk: String,
+    pub new_ltk: String,
+    pub note: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrateOptions {
+    pub from: String,
+    pub to: String,
+    /// Also write the matching Windows registry changes to this `.reg` file.
+    pub reg: Option<PathBuf>,
+}
+
+/// The Windows pairing for a Linux device: by name, preferring the one with the same
+/// address, or by address for keys Windows has no name for.
+pub fn find_windows_device<'a>(
+    bt_device_info: &'a DeviceMap,
+    name: &str,
+    mac: &str
+) -> Option<&'a BtDeviceInfo> {
+    bt_device_info.values()
+        .filter(|info| info.name == name)
+        .min_by(|a, b| (a.mac != mac, &a.adapter, &a.mac).cmp(&(b.mac != mac, &b.adapter, &b.mac)))
+        .or_else(|| bt_device_info.values().find(|info| info.mac == mac && info.name == mac))
+}
+
+pub fn get_ltk(c: &str) -> String {
+    KeyFile::parse(c).get("LongTermKey", "Key").unwrap_or_default().to_string()
+}
+
+pub fn get_link_key(c: &str) -> String {
+    KeyFile::parse(c).get("LinkKey", "Key").unwrap_or_default().to_string()
+}
+
+/// Reads the keys of a BlueZ `info` file; `adapter` and `mac` are left for the caller.
+pub fn read_bt_info(c: &str) -> BtDeviceInfo {
+    let keyfile = KeyFile::parse(c);
+    let get = |group: &str, key: &str| keyfile.get(group, key).unwrap_or_default().to_string();
+    let peripheral = [PeripheralKeySection::Peripheral.name(), PeripheralKeySection::Slave.name()]
+        .into_iter()
+        .find(|name| keyfile.has_group(name))
+        .unwrap_or_default();
+
+    BtDeviceInfo {
+        ltk: get("LongTermKey", "Key"),
+        erand: get("LongTermKey", "Rand"),
+        ediv: get("LongTermKey", "EDiv"),
+        irk: get("IdentityResolvingKey", "Key"),
+        address_type: keyfile.get("General", "AddressType").unwrap_or("public").to_string(),
+        enc_size: get("LongTermKey", "EncSize"),
+        link_key: get("LinkKey", "Key"),
+        link_key_type: get("LinkKey", "Type"),
+        authenticated: keyfile.get("LongTermKey", "Authenticated").and_then(|value| value.parse::<u8>().ok()).map(|value| value & 1 == 1),
+        peripheral_ltk: get(peripheral, "Key"),
+        peripheral_erand: get(peripheral, "Rand"),
+        peripheral_ediv: get(peripheral, "EDiv"),
+        class: get("General", "Class"),
+        appearance: get("General", "Appearance"),
+        device_id_source: get("DeviceID", "Source"),
+        vendor: get("DeviceID", "Vendor"),
+        product: get("DeviceID", "Product"),
+        version: get("DeviceID", "Version"),
+        ..Default::default()
     }
-    return "".to_string();
 }
 
 pub fn fmt_mac(mac: &str) -> String {
-    mac.as_bytes()
+    mac.chars()
+       .collect::<Vec<char>>()
        .chunks(2)
-       .map(|chunk| std::str::from_utf8(chunk).unwrap().to_uppercase())
+       .map(|chunk| chunk.iter().collect::<String>().to_uppercase())
        .collect::<Vec<String>>()
        .join(":")
 }
 
-pub fn update_bt_info(c: &str, info: &BtDeviceInfo) -> String {
-    let mut in_ltk = false;
-    let mut updated = String::with_capacity(c.len() + 50);
+pub fn normalize_mac(mac: &str) -> String {
+    if mac.contains(':') {
+        mac.to_uppercase()
+    } else {
+        fmt_mac(mac)
+    }
+}
 
-    for line in c.lines() {
-        if line.starts_with('[') {
-            in_ltk = line == "[LongTermKey]";
-        }
+pub fn update_bt_info(c: &str, info: &BtDeviceInfo, section: PeripheralKeySection) -> String {
+    let mut keyfile = KeyFile::parse(c);
+
+    if !info.link_key.is_empty() {
+        set_link_key(&mut keyfile, info);
+    }
+    if !info.ltk.is_empty() {
+        set_le_keys(&mut keyfile, info, section);
+    }
+    set_metadata(&mut keyfile, info);
 
-        if in_ltk {
-            match line.splitn(2, '=').next() {
-                Some("Key") => { updated.push_str(&format!("Key={}\n", info.ltk)); continue; }
-                Some("EDiv") => { updated.push_str(&format!("EDiv={}\n", info.ediv)); continue; }
-                Some("Rand") => { updated.push_str(&format!("Rand={}\n", info.erand)); continue; }
-                _ => {}
+    // Only widen an explicit list; without one bluetoothd works it out from the keys.
+    if let Some(current) = keyfile.get("General", "SupportedTechnologies") {
+        let mut technologies: Vec<String> = current.split(';').filter(|t| !t.is_empty()).map(String::from).collect();
+        let before = technologies.len();
+        for technology in info.technologies() {
+            if !technologies.iter().any(|t| t == technology) {
+                technologies.push(technology.to_string());
             }
         }
+        if technologies.len() != before {
+            keyfile.set("General", "SupportedTechnologies", &format!("{};", technologies.join(";")));
+        }
+    }
+
+    keyfile.to_string()
+}
+
+/// Replaces what the desktop uses for the icon and drivers for matching, where Windows knows it.
+fn set_metadata(keyfile: &mut KeyFile, info: &BtDeviceInfo) {
+    if !info.class.is_empty() {
+        keyfile.set("General", "Class", &info.class);
+    }
+    if !info.appearance.is_empty() {
+        keyfile.set("General", "Appearance", &info.appearance);
+    }
+    if let Some([source, vendor, product, version]) = info.device_id() {
+        keyfile.set("DeviceID", "Source", source);
+        keyfile.set("DeviceID", "Vendor", vendor);
+        keyfile.set("DeviceID", "Product", product);
+        keyfile.set("DeviceID", "Version", version);
+    }
+}
+
+fn set_link_key(keyfile: &mut KeyFile, info: &BtDeviceInfo) {
+    keyfile.set("LinkKey", "Key", &info.link_key);
+    // Windows does not keep the key type.
+    if !info.link_key_type.is_empty() {
+        keyfile.set("LinkKey", "Type", &info.link_key_type);
+    } else if keyfile.get("LinkKey", "Type").is_none() {
+        keyfile.set("LinkKey", "Type", DEFAULT_LINK_KEY_TYPE);
+    }
+    if keyfile.get("LinkKey", "PINLength").is_none() {
+        keyfile.set("LinkKey", "PINLength", "0");
+    }
+}
+
+fn set_le_keys(keyfile: &mut KeyFile, info: &BtDeviceInfo, section: PeripheralKeySection) {
+    // BlueZ keeps the mgmt key type here: 0/1 legacy, 2/3 P-256, the odd ones MITM-protected.
+    // Sources that do not record MITM protection, like Windows, keep what the file says.
+    let pairing_type = info.pairing_type();
+    let mitm = match info.authenticated {
+        Some(authenticated) => u8::from(authenticated),
+        None => keyfile.get("LongTermKey", "Authenticated").and_then(|value| value.parse::<u8>().ok()).map_or(0, |value| value & 1),
+    };
+    let key_type = match pairing_type {
+        PairingType::Legacy => 0,
+        PairingType::SecureConnections => 2,
+    } + mitm;
+    let authenticated = key_type.to_string();
+    let same_pairing = keyfile.get("LongTermKey", "Key") == Some(info.ltk.as_str());
+
+    keyfile.set("LongTermKey", "Key", &info.ltk);
+    keyfile.set("LongTermKey", "Authenticated", &authenticated);
+    if !info.enc_size.is_empty() || keyfile.get("LongTermKey", "EncSize").is_none() {
+        keyfile.set("LongTermKey", "EncSize", if info.enc_size.is_empty() { "16" } else { &info.enc_size });
+    }
+    keyfile.set("LongTermKey", "EDiv", if pairing_type == PairingType::SecureConnections { "0" } else { &info.ediv });
+    keyfile.set("LongTermKey", "Rand", if pairing_type == PairingType::SecureConnections { "0" } else { &info.erand });
+
+    let names = [PeripheralKeySection::Peripheral.name(), PeripheralKeySection::Slave.name()];
+    let present: Vec<&str> = names.into_iter().filter(|name| keyfile.has_group(name)).collect();
+
+    // A Secure Connections LTK serves both roles.
+    if pairing_type == PairingType::SecureConnections {
+        for name in names {
+            keyfile.remove_group(name);
+        }
+        return;
+    }
+
+    let entries: Vec<(&str, String)> = if !info.peripheral_ltk.is_empty() {
+        vec![
+            ("Key", info.peripheral_ltk.clone()),
+            ("Authenticated", authenticated),
+            ("EncSize", if info.enc_size.is_empty() { "16" } else { &info.enc_size }.to_string()),
+            ("EDiv", info.peripheral_ediv.clone()),
+            ("Rand", info.peripheral_erand.clone()),
+        ]
+    } else if same_pairing && !present.is_empty() {
+        // Sources without the peripheral key, like Windows, leave the file's in place while it
+        // belongs to the same pairing. In a mixed file the installed bluetoothd's section wins.
+        let from = if present.len() == 1 { present[0] } else { section.name() };
+        ["Key", "Authenticated", "EncSize", "EDiv", "Rand"]
+            .into_iter()
+            .filter_map(|key| Some((key, keyfile.get(from, key)?.to_string())))
+            .collect()
+    } else {
+        // After a legacy re-pairing the key left in the file is stale.
+        for name in names {
+            keyfile.remove_group(name);
+        }
+        return;
+    };
+
+    // Files carried over from older BlueZ versions may hold either name or both; keep
+    // whatever is there in step, and only fall back to the installed naming for new files.
+    let targets = if present.is_empty() { vec![section.name()] } else { present };
+    for name in targets {
+        for (key, value) in &entries {
+            keyfile.set(name, key, value);
+        }
+    }
+}
+
+pub fn new_bt_info(name: &str, info: &BtDeviceInfo, section: PeripheralKeySection) -> String {
+    let mut keyfile = KeyFile::default();
+    // Devices Windows has no name for are named after their address; bluetoothd fills
+    // the name in on the next connection.
+    if name != info.mac {
+        keyfile.set("General", "Name", name);
+    }
+    keyfile.set("General", "AddressType", &info.address_type);
+    keyfile.set("General", "SupportedTechnologies", &format!("{};", info.technologies().join(";")));
+    keyfile.set("General", "Trusted", "true");
+    set_metadata(&mut keyfile, info);
 
-        updated.push_str(line);
-        updated.push('\n');
+    if !info.link_key.is_empty() {
+        set_link_key(&mut keyfile, info);
     }
 
-    updated
+    if !info.irk.is_empty() {
+        keyfile.set("IdentityResolvingKey", "Key", &info.irk);
+    }
+
+    if !info.ltk.is_empty() {
+        set_le_keys(&mut keyfile, info, section);
+    }
+    keyfile.to_string()
+}
+
+pub fn update_identity(c: &str, local_irk: &str) -> String {
+    let mut keyfile = KeyFile::parse(c);
+    keyfile.set("General", "IdentityResolvingKey", local_irk);
+    keyfile.to_string()
 }
 
-fn restart_bluetooth_service() {
+pub fn new_bt_cache(name: &str) -> String {
+    let mut keyfile = KeyFile::default();
+    keyfile.set("General", "Name", name);
+    keyfile.to_string()
+}
+
+pub(crate) fn restart_bluetooth_service() {
     if std::env::var("TESTING").is_ok() { return;}
 
     let output = Command::new("systemctl").args(["restart", "bluetooth"]).output().expect("Failed to execute command");
@@ -73,57 +526,157 @@ fn restart_bluetooth_service() {
     }
 }
 
-pub fn process_bth_device(path: PathBuf, bt_device_info: &HashMap<String, BtDeviceInfo>) -> Result<(), Box<dyn std::error::Error>> {
-    let mut result_map = HashMap::new();
-    let has_updates = process_directories(&path, bt_device_info, &mut result_map)?;
+pub fn process_bth_device(
+    path: PathBuf,
+    bt_device_info: &DeviceMap,
+    options: &SyncOptions
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let results = update_devices(&path, bt_device_info, options)?;
 
-    if !has_updates {
+    if results.is_empty() {
         println!("\n=== NO Linux bluetooth info found from {} ===", path.display());
-        return Ok(())
+        return Ok(false)
     }
 
-    print_update_results(&result_map);
-    restart_bluetooth_service();
-    Ok(())
+    print_update_results(&results);
+    Ok(true)
 }
 
-fn process_directories(
-    path: &Path,
-    bt_device_info: &HashMap<String, BtDeviceInfo>,
-    result_map: &mut HashMap<String, (String, String, String, String)>
+pub fn import_bth_device(
+    path: PathBuf,
+    bt_device_info: &DeviceMap,
+    options: &SyncOptions
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    let mut has_updates = false;
+    let mut known_names = Vec::new();
+    let mut known_macs = Vec::new();
 
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let sub_path = entry.path();
+    for sub_path in device_directories(&path)? {
+        known_macs.push(sub_path.file_name().unwrap().to_string_lossy().into_owned());
+        if let Some((name, _)) = read_device_info(&sub_path)? {
+            known_names.push(name);
+        }
+    }
 
-        if is_valid_device_directory(&sub_path) {
-            if let Some((name, content)) = read_device_info(&sub_path)? {
-                if let Some(info) = bt_device_info.get(&name) {
-                    update_device_info(&name, &sub_path, &content, info, result_map)?;
-                    has_updates = true;
-                }
+    let mut imported = Vec::new();
+    for info in bt_device_info.values() {
+        if known_names.contains(&info.name) || known_macs.contains(&info.mac) {
+            continue;
+        }
+        create_device_info(&path, &info.name, info, options.peripheral_section)?;
+        imported.push((info.name.clone(), info.mac.clone(), info.display_key().to_string()));
+    }
+
+    if imported.is_empty() {
+        println!("\n=== NO Windows-only bluetooth devices to import into {} ===", path.display());
+        return Ok(false)
+    }
+
+    print_import_results(&imported);
+    Ok(true)
+}
+
+/// Compares every device paired on both sides of `path` and picks the newer pairing:
+/// the hive key's last-written time against the mtime of the BlueZ `info` file.
+pub fn decide_sync_direction(
+    path: &Path,
+    bt_device_info: &DeviceMap
+) -> Result<Vec<SyncDecision>, Box<dyn std::error::Error>> {
+    let mut decisions = Vec::new();
+
+    for sub_path in device_directories(path)? {
+        let mac = sub_path.file_name().unwrap().to_string_lossy().into_owned();
+        let (name, content) = match read_device_info(&sub_path) {
+            Ok(Some(found)) => found,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}", yellow!("Warning: cannot read {}: {}, skipping", sub_path.join("info").display(), e));
+                continue;
+            }
+        };
+        let Some(windows) = find_windows_device(bt_device_info, &name, &mac) else { continue };
+
+        let linux = BtDeviceInfo {
+            name: name.clone(),
+            adapter: windows.adapter.clone(),
+            mac,
+            last_written: fs::metadata(sub_path.join("info"))?.modified().ok(),
+            ..read_bt_info(&content)
+        };
+        let windows_time = windows.last_written.map_or("unknown".to_string(), format_timestamp);
+        let linux_time = linux.last_written.map_or("unknown".to_string(), format_timestamp);
+
+        let (source, reason) = if linux.mac == windows.mac && linux.same_keys(windows) {
+            (SyncSource::Unchanged, "same address and key on both sides".to_string())
+        } else if windows.last_written.is_none() || linux.last_written.is_none() {
+            (SyncSource::Windows, format!("no timestamp to compare (Windows {}, Linux {}), keeping Windows", windows_time, linux_time))
+        } else if linux.last_written > windows.last_written {
+            (SyncSource::Linux, format!("re-paired on Linux at {}, Windows key is from {}", linux_time, windows_time))
+        } else {
+            (SyncSource::Windows, format!("re-paired on Windows at {}, Linux key is from {}", windows_time, linux_time))
+        };
+
+        decisions.push(SyncDecision { name, source, reason, windows: windows.clone(), linux });
+    }
+
+    decisions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(decisions)
+}
+
+/// Updates every device directory under `path` that has a pairing in `bt_device_info`,
+/// in address order, and returns what was done to each.
+pub fn update_devices(
+    path: &Path,
+    bt_device_info: &DeviceMap,
+    options: &SyncOptions
+) -> Result<Vec<UpdateResult>, Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    let mut updated = Vec::new();
+
+    for sub_path in device_directories(path)? {
+        // A merge or replace earlier in this walk may have consumed or produced this directory.
+        if !sub_path.exists() || updated.contains(&sub_path) {
+            continue;
+        }
+
+        let mac = sub_path.file_name().unwrap().to_string_lossy().into_owned();
+        if let Some((name, content)) = read_device_info(&sub_path)? {
+            if let Some(info) = find_windows_device(bt_device_info, &name, &mac) {
+                let result = update_device_info(&name, &sub_path, &content, info, options)?;
+                // A skipped directory keeps its address, so the one it collided with is still to be visited.
+                updated.push(path.join(&result.new_mac));
+                results.push(result);
             }
         }
     }
 
-    Ok(has_updates)
+    Ok(results)
+}
+
+pub(crate) fn device_directories(path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut directories = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let sub_path = entry?.path();
+        if is_valid_device_directory(&sub_path) {
+            directories.push(sub_path);
+        }
+    }
+
+    directories.sort();
+    Ok(directories)
 }
 
 fn is_valid_device_directory(sub_path: &Path) -> bool {
-    sub_path.is_dir() && sub_path.file_name().and_then(|f| f.to_str()).map_or(false, |name| name.contains(':'))
+    sub_path.is_dir() && sub_path.file_name().and_then(|f| f.to_str()).is_some_and(|name| name.contains(':'))
 }
 
-fn read_device_info(sub_path: &Path) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+pub(crate) fn read_device_info(sub_path: &Path) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
     let info_path = sub_path.join("info");
     let content = fs::read_to_string(&info_path)?;
-    if let Some(captures) = Regex::new(r"(?m)^Name=(.*)$")?.captures(&content) {
-        if let Some(name_match) = captures.get(1) {
-            return Ok(Some((name_match.as_str().to_string(), content)));
-        }
+    match KeyFile::parse(&content).get("General", "Name") {
+        Some(name) => Ok(Some((name.to_string(), content))),
+        None => Ok(None),
     }
-    Ok(None)
 }
 
 fn update_device_info(
@@ -131,26 +684,173 @@ fn update_device_info(
     sub_path: &Path,
     content: &str,
     info: &BtDeviceInfo,
-    result_map: &mut HashMap<String, (String, String, String, String)>
-) -> Result<(), Box<dyn std::error::Error>> {
-    let new_content = update_bt_info(content, info);
-    fs::write(sub_path.join("info"), &new_content)?;
-    fs::rename(sub_path, sub_path.parent().unwrap().join(&info.mac))?;
-
-    result_map.insert(
-        name.to_string(),
-        (
-            sub_path.file_name().unwrap().to_string_lossy().into_owned(),
-            info.mac.clone(),
-            get_ltk(content),
-            info.ltk.clone()
-        )
-    );
+    options: &SyncOptions
+) -> Result<UpdateResult, Box<dyn std::error::Error>> {
+    let parent = sub_path.parent().unwrap();
+    let target = parent.join(&info.mac);
+    let old_mac = sub_path.file_name().unwrap().to_string_lossy().into_owned();
+    let mut new_content = update_bt_info(content, info, options.peripheral_section);
+
+    let mut result = UpdateResult {
+        name: name.to_string(),
+        old_mac: old_mac.clone(),
+        new_mac: info.mac.clone(),
+        old_ltk: if info.ltk.is_empty() { get_link_key(content) } else { get_ltk(content) },
+        new_ltk: info.display_key().to_string(),
+        note: String::new(),
+    };
+
+    if target != sub_path && target.exists() {
+        match options.on_collision {
+            CollisionPolicy::Skip => {
+                result.new_mac = old_mac;
+                result.new_ltk = result.old_ltk.clone();
+                result.note = format!("skipped, {} already exists", info.mac);
+                return Ok(result);
+            }
+            CollisionPolicy::Replace => {
+                fs::remove_dir_all(&target)?;
+                result.note = format!("replaced existing {}", info.mac);
+            }
+            CollisionPolicy::Merge => {
+                if let Ok(existing) = fs::read_to_string(target.join("info")) {
+                    new_content = merge_sections(&new_content, &existing);
+                }
+                for entry in fs::read_dir(sub_path)? {
+                    let entry = entry?;
+                    if entry.file_name() != "info" && !target.join(entry.file_name()).exists() {
+                        rename_durable(entry.path(), target.join(entry.file_name()))?;
+                    }
+                }
+                write_atomic(target.join("info"), &new_content)?;
+                fs::remove_dir_all(sub_path)?;
+                move_cache_entry(&parent.join("cache"), &old_mac, &info.mac, name)?;
+
+                result.note = format!("merged into existing {}", info.mac);
+                return Ok(result);
+            }
+        }
+    }
+
+    // Renamed first: a crash in between leaves the old keys under the new address, which
+    // the next run updates, rather than new keys under the old one.
+    rename_durable(sub_path, &target)?;
+    write_atomic(target.join("info"), &new_content)?;
+    move_cache_entry(&parent.join("cache"), &old_mac, &info.mac, name)?;
+
+    Ok(result)
+}
+
+fn move_cache_entry(cache_path: &Path, old_mac: &str, new_mac: &str, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let old_path = cache_path.join(old_mac);
+    let new_path = cache_path.join(new_mac);
+
+    let content = match (old_path.is_file(), new_path.is_file()) {
+        (false, false) => return Ok(()),
+        (true, false) => fs::read_to_string(&old_path)?,
+        (false, true) => fs::read_to_string(&new_path)?,
+        (true, true) if old_path == new_path => fs::read_to_string(&old_path)?,
+        (true, true) => merge_sections(&fs::read_to_string(&old_path)?, &fs::read_to_string(&new_path)?),
+    };
+
+    write_atomic(&new_path, set_cache_name(&content, name))?;
+    if old_path != new_path && old_path.exists() {
+        fs::remove_file(&old_path)?;
+    }
+
+    Ok(())
+}
+
+/// Keeps every section of `primary` and adds the sections only `secondary` has.
+pub fn merge_sections(primary: &str, secondary: &str) -> String {
+    let mut merged = KeyFile::parse(primary);
+    merged.add_missing_groups(&KeyFile::parse(secondary));
+    merged.to_string()
+}
+
+pub fn set_cache_name(c: &str, name: &str) -> String {
+    let mut keyfile = KeyFile::parse(c);
+    keyfile.set("General", "Name", name);
+    keyfile.to_string()
+}
+
+fn create_device_info(path: &Path, name: &str, info: &BtDeviceInfo, section: PeripheralKeySection) -> Result<(), Box<dyn std::error::Error>> {
+    let dev_path = path.join(&info.mac);
+    fs::create_dir_all(&dev_path)?;
+    fs::set_permissions(&dev_path, fs::Permissions::from_mode(0o700))?;
+    write_atomic(dev_path.join("info"), new_bt_info(name, info, section))?;
+
+    let cache_path = path.join("cache");
+    fs::create_dir_all(&cache_path)?;
+    if name != info.mac && !cache_path.join(&info.mac).exists() {
+        write_atomic(cache_path.join(&info.mac), new_bt_cache(name))?;
+    }
 
     Ok(())
 }
 
-fn print_update_results(result_map: &HashMap<String, (String, String, String, String)>) {
+/// Writes the adapter's `identity` file, keeping the replaced one as `identity.bak`.
+pub fn sync_adapter_identity(path: &Path, local_irk: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let identity_path = path.join("identity");
+    let content = fs::read_to_string(&identity_path).unwrap_or_default();
+
+    let current = KeyFile::parse(&content).get("General", "IdentityResolvingKey").unwrap_or_default().to_uppercase();
+    if local_irk.is_empty() || current == local_irk {
+        return Ok(false);
+    }
+
+    if identity_path.exists() {
+        fs::copy(&identity_path, path.join("identity.bak"))?;
+    }
+    write_atomic(&identity_path, update_identity(&content, local_irk))?;
+
+    println!("{}", green!("\n=== Update Linux adapter identity ==="));
+    println!("{} | FROM {}", rgb!(0xf0, 0x00, 0x56, "{:<30}", path.file_name().unwrap().to_string_lossy()), rgb!(0xaa, 0x96, 0xda, "{:<40}", current));
+    println!("{} |   TO {}", rgb!(0xf0, 0x00, 0x56, "{:<30}", " "), rgb!(0x00, 0xe0, 0x79, "{:<40}", local_irk));
+
+    Ok(true)
+}
+
+fn print_import_results(imported: &[(String, String, String)]) {
+    println!("{}", green!("\n=== Import Windows-only bluetooth devices ==="));
+
+    println!("{} |      {} |      {}",
+        blue!("{:<30}", "Device Name"),
+        blue!("{:<24}", "Address"),
+        blue!("{:<40}", "Key"));
+    println!("{}", "-".repeat(102));
+
+    for (name, mac, ltk) in imported {
+        println!("{} |  NEW {} |  NEW {}",
+            rgb!(0xf0, 0x00, 0x56, "{:<30}", name),
+            rgb!(0x00, 0xe0, 0x79, "{:<24}", mac),
+            rgb!(0x00, 0xe0, 0x79, "{:<40}", ltk));
+    }
+}
+
+fn print_sync_decisions(path: &Path, decisions: &[SyncDecision]) {
+    println!("{}", green!("\n=== Sync direction for {} ===", path.display()));
+
+    println!("{} |      {} |      {}",
+        blue!("{:<30}", "Device Name"),
+        blue!("{:<24}", "Source"),
+        blue!("{:<40}", "Reason"));
+    println!("{}", "-".repeat(102));
+
+    for decision in decisions {
+        let source = match decision.source {
+            SyncSource::Windows => "Windows -> Linux",
+            SyncSource::Linux => "Linux -> Windows",
+            SyncSource::Unchanged => "in sync",
+        };
+        println!("{} |      {} |      {}",
+            rgb!(0xf0, 0x00, 0x56, "{:<30}", decision.name),
+            rgb!(0x00, 0xe0, 0x79, "{:<24}", source),
+            rgb!(0xaa, 0x96, 0xda, "{}", decision.reason));
+    }
+}
+
+fn print_update_results(results: &[UpdateResult]) {
     println!("{}", green!("\n=== Update Linux bluetooth info ==="));
 
     println!("{} |      {} |      {}", 
@@ -159,32 +859,341 @@ fn print_update_results(result_map: &HashMap<String, (String, String, String, St
         blue!("{:<40}", "Key"));
     println!("{}", "-".repeat(102));
     
-    for (name, (old_mac, new_mac, old_ltk, new_ltk)) in result_map {
-        let name_colored = rgb!(0xf0, 0x00, 0x56, "{:<30}", name);
-        let old_mac_colored = rgb!(0xaa, 0x96, 0xda, "{:<24}", old_mac);
-        let old_ltk_colored = rgb!(0xaa, 0x96, 0xda, "{:<40}", old_ltk);
+    for result in results {
+        let name_colored = rgb!(0xf0, 0x00, 0x56, "{:<30}", result.name);
+        let old_mac_colored = rgb!(0xaa, 0x96, 0xda, "{:<24}", result.old_mac);
+        let old_ltk_colored = rgb!(0xaa, 0x96, 0xda, "{:<40}", result.old_ltk);
         println!("{} | FROM {} | FROM {}", name_colored, old_mac_colored, old_ltk_colored);
 
         let space_colored = rgb!(0xf0, 0x00, 0x56, "{:<30}", " ");
-        let new_mac_colored = rgb!(0x00, 0xe0, 0x79, "{:<24}", new_mac);
-        let new_ltk_colored = rgb!(0x00, 0xe0, 0x79, "{:<40}", new_ltk);
+        let new_mac_colored = rgb!(0x00, 0xe0, 0x79, "{:<24}", result.new_mac);
+        let new_ltk_colored = rgb!(0x00, 0xe0, 0x79, "{:<40}", result.new_ltk);
         println!("{} |   TO {} |   TO {}", space_colored, new_mac_colored, new_ltk_colored);
+
+        if !result.note.is_empty() {
+            println!("{} | NOTE {}", space_colored, yellow!("{}", result.note));
+        }
     }
 }
 
-pub fn process_bluetooth_devices(bt_dir_path: &str) -> Result<()> {
-    let bt_device_info = find_and_mount_ntfs_partitions()?;
-    if bt_device_info.is_empty() {
-        eprintln!("No LTK to show.");
-        return Ok(());
+pub fn resolve_adapter_map(
+    bt_device_info: &DeviceMap,
+    linux_adapters: &[String],
+    options: &SyncOptions
+) -> HashMap<String, String> {
+    let mut windows_adapters: Vec<String> = bt_device_info.values()
+        .map(|info| info.adapter.clone())
+        .filter(|adapter| !adapter.is_empty())
+        .collect();
+    windows_adapters.sort();
+    windows_adapters.dedup();
+
+    let mut adapter_map = HashMap::new();
+    for adapter in &windows_adapters {
+        let target = options.adapter_map.get(adapter).cloned().unwrap_or_else(|| adapter.clone());
+        adapter_map.insert(adapter.clone(), target);
+    }
+
+    // A single radio on each side is the same controller seen by both systems,
+    // even when the two report different addresses.
+    if options.adapter_map.is_empty() && windows_adapters.len() == 1 && linux_adapters.len() == 1
+        && windows_adapters[0] != linux_adapters[0] {
+        println!("{}", yellow!("Mapping Windows adapter {} to Linux adapter {}", windows_adapters[0], linux_adapters[0]));
+        adapter_map.insert(windows_adapters[0].clone(), linux_adapters[0].clone());
+    }
+
+    for (windows, linux) in &adapter_map {
+        if !linux_adapters.contains(linux) {
+            eprintln!("{}", yellow!("Windows adapter {} has no Linux adapter directory {}, use --adapter-map {}=<linux adapter>", windows, linux, windows));
+        }
     }
 
+    adapter_map
+}
+
+pub fn devices_for_adapter(
+    linux_adapter: &str,
+    bt_device_info: &DeviceMap,
+    adapter_map: &HashMap<String, String>
+) -> DeviceMap {
+    bt_device_info.iter()
+        .filter(|(_, info)| info.adapter.is_empty() || adapter_map.get(&info.adapter).is_some_and(|a| a == linux_adapter))
+        .map(|(key, info)| (key.clone(), info.clone()))
+        .collect()
+}
+
+/// Adapter directories under `bt_dir_path`, with the upper-case name used for matching.
+pub(crate) fn adapter_directories(bt_dir_path: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut adapter_paths = Vec::new();
     for entry in fs::read_dir(bt_dir_path)? {
         let path = entry?.path();
         if path.is_dir() {
-            let _ = process_bth_device(path, &bt_device_info);
+            adapter_paths.push((path.file_name().unwrap().to_string_lossy().to_uppercase(), path));
         }
     }
-    
+    adapter_paths.sort();
+    Ok(adapter_paths)
+}
+
+/// Compares every Windows pairing with every BlueZ `info` file, without changing either.
+pub fn pairing_status(
+    bt_dir_path: &Path,
+    bt_device_info: &DeviceMap,
+    options: &SyncOptions
+) -> Result<Vec<DeviceStatus>, Box<dyn std::error::Error>> {
+    let adapter_paths = adapter_directories(bt_dir_path)?;
+    let linux_adapters: Vec<String> = adapter_paths.iter().map(|(adapter, _)| adapter.clone()).collect();
+    let adapter_map = resolve_adapter_map(bt_device_info, &linux_adapters, options);
+
+    let mut statuses = Vec::new();
+    let mut matched = Vec::new();
+
+    for (adapter, path) in &adapter_paths {
+        let adapter_info = devices_for_adapter(adapter, bt_device_info, &adapter_map);
+
+        for sub_path in device_directories(path)? {
+            let mac = sub_path.file_name().unwrap().to_string_lossy().into_owned();
+            let (name, content) = match read_device_info(&sub_path) {
+                Ok(Some(found)) => found,
+                Ok(None) => {
+                    statuses.push(DeviceStatus { name: mac.clone(), adapter: adapter.clone(), mac, state: DeviceState::LinuxOnly });
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("{}", yellow!("Warning: cannot read {}: {}, skipping", sub_path.join("info").display(), e));
+                    continue;
+                }
+            };
+
+            let windows = find_windows_device(&adapter_info, &name, &mac);
+            let state = match windows {
+                None => DeviceState::LinuxOnly,
+                Some(windows) => {
+                    let linux = read_bt_info(&content);
+                    let differs = |a: &str, b: &str| !a.is_empty() && !a.eq_ignore_ascii_case(b);
+                    if windows.mac != mac {
+                        DeviceState::AddressDiffers
+                    } else if differs(&windows.ltk, &linux.ltk) || differs(&windows.link_key, &linux.link_key) {
+                        DeviceState::KeyDiffers
+                    } else {
+                        DeviceState::InSync
+                    }
+                }
+            };
+            if let Some(windows) = windows {
+                matched.push(windows.key());
+            }
+            statuses.push(DeviceStatus { name, adapter: adapter.clone(), mac, state });
+        }
+    }
+
+    for (key, info) in bt_device_info {
+        if !matched.contains(key) {
+            let adapter = adapter_map.get(&info.adapter).cloned().unwrap_or_else(|| info.adapter.clone());
+            statuses.push(DeviceStatus { name: info.name.clone(), adapter, mac: info.mac.clone(), state: DeviceState::WindowsOnly });
+        }
+    }
+
+    statuses.sort_by(|a, b| (&a.adapter, &a.name).cmp(&(&b.adapter, &b.name)));
+    Ok(statuses)
+}
+
+/// Prints the devices read from `source`, `system` naming what kind of store it is.
+pub(crate) fn print_devices(system: &str, source: &str, bt_device_info: &DeviceMap) {
+    println!("{}", green!("=== Get {} bluetooth info from {} ===", system, red!("{}", source)));
+
+    println!("{} |      {} |      {}", blue!("{:<30}", "Device Name"), blue!("{:<24}", "Address"), blue!("{:<40} ", "Key"));
+    println!("{}", "-".repeat(102));
+    let mut devices: Vec<&BtDeviceInfo> = bt_device_info.values().collect();
+    devices.sort_by(|a, b| (&a.name, &a.adapter, &a.mac).cmp(&(&b.name, &b.adapter, &b.mac)));
+    for info in devices {
+        println!("{} |      {} |      {}",
+            rgb!(0xf0, 0x00, 0x56, "{:<30}", info.name),
+            rgb!(0xaa, 0x96, 0xda, "{:<24}", info.mac),
+            rgb!(0xaa, 0x96, 0xda, "{:<40}", info.display_key()));
+    }
+}
+
+fn print_status(statuses: &[DeviceStatus]) {
+    println!("{}", green!("\n=== Bluetooth pairing status ==="));
+
+    println!("{} |      {} |      {}",
+        blue!("{:<30}", "Device Name"),
+        blue!("{:<24}", "Address"),
+        blue!("{:<40}", "State"));
+    println!("{}", "-".repeat(102));
+
+    for status in statuses {
+        let state = match status.state {
+            DeviceState::InSync => green!("{:<40}", status.state.label()),
+            _ => yellow!("{:<40}", status.state.label()),
+        };
+        println!("{} |      {} |      {}",
+            rgb!(0xf0, 0x00, 0x56, "{:<30}", status.name),
+            rgb!(0xaa, 0x96, 0xda, "{:<24}", status.mac),
+            state);
+    }
+}
+
+/// Pairings to apply, from the source the options name.
+pub(crate) fn read_source_devices(bt_dir_path: &str, options: &SyncOptions) -> Result<DeviceMap> {
+    open_source(bt_dir_path, options)?.read()
+}
+
+/// Prints the status table and returns whether every device is in sync.
+pub fn process_status(bt_dir_path: &str, options: &SyncOptions) -> Result<bool> {
+    let bt_device_info = read_source_devices(bt_dir_path, options)?;
+    let statuses = pairing_status(Path::new(bt_dir_path), &bt_device_info, options)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", bt_dir_path, e))?;
+    print_status(&statuses);
+    Ok(statuses.iter().all(|status| status.state == DeviceState::InSync))
+}
+
+pub fn process_bluetooth_devices(bt_dir_path: &str, options: &SyncOptions) -> Result<()> {
+    let source = open_source(bt_dir_path, options)?;
+    let sink = open_sink(bt_dir_path, options)?;
+    sync_pairings(source.as_ref(), sink.as_ref(), options)?;
+    Ok(())
+}
+
+/// Applies `bt_device_info` to the BlueZ state under `bt_dir_path` and returns whether
+/// anything changed. Restarting bluetoothd is left to the caller.
+pub fn apply_to_bluez(bt_dir_path: &Path, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+    let adapter_paths = adapter_directories(bt_dir_path)?;
+    let linux_adapters: Vec<String> = adapter_paths.iter().map(|(adapter, _)| adapter.clone()).collect();
+    let adapter_map = resolve_adapter_map(bt_device_info, &linux_adapters, options);
+
+    let mut changed = false;
+    let mut windows_updates = Vec::new();
+    for (adapter, path) in adapter_paths {
+        let mut adapter_info = devices_for_adapter(&adapter, bt_device_info, &adapter_map);
+        if adapter_info.is_empty() {
+            continue;
+        }
+
+        if options.direction == SyncDirection::Auto {
+            let decisions = decide_sync_direction(&path, &adapter_info)
+                .map_err(|e| anyhow::anyhow!("Failed to compare pairings in {}: {}", path.display(), e))?;
+            print_sync_decisions(&path, &decisions);
+            for decision in decisions.into_iter().filter(|d| d.source == SyncSource::Linux) {
+                adapter_info.retain(|_, info| *info != decision.windows);
+                windows_updates.push(decision);
+            }
+        }
+
+        changed |= process_bth_device(path.clone(), &adapter_info, options)
+            .map_err(|e| anyhow::anyhow!("Failed to update devices in {}: {}", path.display(), e))?;
+        if let Some(local_irk) = adapter_info.values().map(|info| &info.local_irk).find(|irk| !irk.is_empty()) {
+            changed |= sync_adapter_identity(&path, local_irk)
+                .map_err(|e| anyhow::anyhow!("Failed to update the identity of {}: {}", path.display(), e))?;
+        }
+        if options.import {
+            changed |= import_bth_device(path.clone(), &adapter_info, options)
+                .map_err(|e| anyhow::anyhow!("Failed to import devices into {}: {}", path.display(), e))?;
+        }
+    }
+
+    if !windows_updates.is_empty() {
+        match &options.reg {
+            Some(reg) => {
+                fs::write(reg, encode_reg(&windows_update_reg(&windows_updates)))?;
+                println!("{}", green!("\n=== Windows registry changes written to {} ===", reg.display()));
+            }
+            None => eprintln!("{}", yellow!("\n{} device(s) are newer on Linux, pass --reg <file> to write them for Windows", windows_updates.len())),
+        }
+    }
+
+    Ok(changed)
+}
+
+pub fn migrate_adapter(bt_dir_path: &Path, from: &str, to: &str) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let from_path = bt_dir_path.join(from);
+    let to_path = bt_dir_path.join(to);
+    if !from_path.is_dir() {
+        return Err(format!("No adapter directory {}", from_path.display()).into());
+    }
+    fs::create_dir_all(&to_path)?;
+    fs::set_permissions(&to_path, fs::Permissions::from_mode(0o700))?;
+
+    let mut results = Vec::new();
+
+    for sub_path in device_directories(&from_path)? {
+        let mac = sub_path.file_name().unwrap().to_string_lossy().into_owned();
+        if to_path.join(&mac).exists() {
+            results.push((mac, format!("skipped, already paired on {}", to)));
+            continue;
+        }
+        rename_durable(&sub_path, to_path.join(&mac))?;
+        results.push((mac, "moved".to_string()));
+    }
+
+    let from_cache = from_path.join("cache");
+    if from_cache.is_dir() {
+        let to_cache = to_path.join("cache");
+        fs::create_dir_all(&to_cache)?;
+        for entry in fs::read_dir(&from_cache)? {
+            let entry = entry?;
+            let item = format!("cache/{}", entry.file_name().to_string_lossy());
+            if to_cache.join(entry.file_name()).exists() {
+                results.push((item, format!("skipped, already cached on {}", to)));
+                continue;
+            }
+            rename_durable(entry.path(), to_cache.join(entry.file_name()))?;
+            results.push((item, "moved".to_string()));
+        }
+        remove_dir_if_empty(&from_cache)?;
+    }
+
+    // The old adapter's settings are the ones the user chose, unless the new adapter
+    // already has settings of its own, which are left alone like its pairings.
+    if from_path.join("settings").is_file() {
+        if to_path.join("settings").exists() {
+            results.push(("settings".to_string(), format!("skipped, already set on {}", to)));
+        } else {
+            rename_durable(from_path.join("settings"), to_path.join("settings"))?;
+            results.push(("settings".to_string(), "moved".to_string()));
+        }
+    }
+
+    remove_dir_if_empty(&from_path)?;
+
+    Ok(results)
+}
+
+fn remove_dir_if_empty(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if fs::read_dir(path)?.next().is_none() {
+        fs::remove_dir(path)?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn print_migrate_results(from: &str, to: &str, results: &[(String, String)]) {
+    println!("{}", green!("\n=== Migrate Linux bluetooth adapter {} to {} ===", from, to));
+
+    println!("{} |      {}", blue!("{:<30}", "Entry"), blue!("{:<40}", "Result"));
+    println!("{}", "-".repeat(102));
+
+    for (item, action) in results {
+        println!("{} |      {}",
+            rgb!(0xf0, 0x00, 0x56, "{:<30}", item),
+            rgb!(0x00, 0xe0, 0x79, "{:<40}", action));
+    }
+}
+
+pub fn process_adapter_migration(bt_dir_path: &str, options: &MigrateOptions) -> Result<()> {
+    let results = migrate_adapter(Path::new(bt_dir_path), &options.from, &options.to)
+        .map_err(|e| anyhow::anyhow!("Failed to migrate adapter {}: {}", options.from, e))?;
+    print_migrate_results(&options.from, &options.to, &results);
+
+    if let Some(reg) = &options.reg {
+        let bt_device_info = find_and_mount_ntfs_partitions()?;
+        fs::write(reg, encode_reg(&migrate_adapter_reg(&options.from, &options.to, &bt_device_info)))?;
+        println!("{}", green!("\n=== Windows registry changes written to {} ===", reg.display()));
+    }
+
+    if !results.is_empty() {
+        restart_bluetooth_service();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::{fs, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use anyhow::Result;
+use term_ansi::*;
+
+use crate::{
+    bluetooth::{adapter_directories, apply_to_bluez, device_directories, read_bt_info, print_devices, restart_bluetooth_service, BtDeviceInfo, DeviceMap, SyncOptions},
+    keyfile::KeyFile,
+    partitions::find_in_partitions,
+    store::{PairingSink, PairingSource},
+};
+
+/// Where to find the BlueZ state of another Linux installation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinuxSource {
+    /// A `/var/lib/bluetooth` style directory.
+    Directory(PathBuf),
+    /// An ext4 or btrfs partition holding a Linux root.
+    Partition(String),
+    /// The first ext4 or btrfs partition with pairings other than the running system's.
+    Discover,
+}
+
+/// Reads every pairing under a BlueZ state directory, named like the Windows devices,
+/// or after their address for devices without a name.
+pub fn read_bluez_tree(bt_dir_path: &Path, source: &str) -> Result<DeviceMap, Box<dyn std::error::Error>> {
+    let mut bt_device_info = DeviceMap::new();
+
+    for (adapter, path) in adapter_directories(bt_dir_path)? {
+        let identity = fs::read_to_string(path.join("identity")).unwrap_or_default();
+        let local_irk = KeyFile::parse(&identity).get("General", "IdentityResolvingKey").unwrap_or_default().to_uppercase();
+
+        for sub_path in device_directories(&path)? {
+            let mac = sub_path.file_name().unwrap().to_string_lossy().to_uppercase();
+            let info_path = sub_path.join("info");
+            let Ok(content) = fs::read_to_string(&info_path) else {
+                eprintln!("{}", yellow!("Warning: cannot read {}, skipping", info_path.display()));
+                continue;
+            };
+
+            let info = BtDeviceInfo {
+                name: KeyFile::parse(&content).get("General", "Name").map_or(mac.clone(), str::to_string),
+                adapter: adapter.clone(),
+                mac: mac.clone(),
+                local_irk: local_irk.clone(),
+                last_written: fs::metadata(&info_path)?.modified().ok(),
+                source: source.to_string(),
+                ..read_bt_info(&content)
+            };
+            if info.ltk.is_empty() && info.link_key.is_empty() {
+                continue;
+            }
+            bt_device_info.insert(info.key(), info);
+        }
+    }
+
+    Ok(bt_device_info)
+}
+
+/// BlueZ state directories on a mounted Linux root, including the usual btrfs subvolumes.
+fn bluez_directories(mountpoint: &Path) -> Vec<PathBuf> {
+    let mut roots = vec![mountpoint.to_path_buf()];
+    if let Ok(entries) = fs::read_dir(mountpoint) {
+        let mut subvolumes: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).filter(|path| path.is_dir()).collect();
+        subvolumes.sort();
+        roots.extend(subvolumes);
+    }
+
+    roots.into_iter()
+        .map(|root| root.join("var/lib/bluetooth"))
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+/// Compares inodes, since the running root may also be reachable through a second mount.
+pub(crate) fn is_same_directory(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+pub fn parse_bluez(source: &str, bt_dir_path: &Path) -> Result<DeviceMap> {
+    let bt_device_info = read_bluez_tree(bt_dir_path, source)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", bt_dir_path.display(), e))?;
+
+    print_devices("Linux", source, &bt_device_info);
+
+    Ok(bt_device_info)
+}
+
+/// Reads the pairings of another Linux installation, never those under `running`.
+pub fn read_linux_devices(source: &LinuxSource, running: &Path) -> Result<DeviceMap> {
+    if let LinuxSource::Directory(path) = source {
+        if is_same_directory(path, running) {
+            anyhow::bail!("{} is the running system's Bluetooth state", path.display());
+        }
+        return parse_bluez(&path.display().to_string(), path);
+    }
+
+    let wanted = match source {
+        LinuxSource::Partition(device) => Some(device.as_str()),
+        _ => None,
+    };
+    // Mounted again, the running root's subvolumes do not always compare equal to their live mount.
+    let bt_device_info = find_in_partitions(&["ext4", "btrfs"], wanted, Some(running), |device, mountpoint| {
+        for path in bluez_directories(Path::new(mountpoint)) {
+            if is_same_directory(&path, running) {
+                continue;
+            }
+            let info = parse_bluez(device, &path)?;
+            if !info.is_empty() {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    })?;
+
+    if bt_device_info.is_none() {
+        eprintln!("{}", yellow!("No other Linux installation with Bluetooth pairings found"));
+    }
+    Ok(bt_device_info.unwrap_or_default())
+}
+
+/// Another Linux installation's BlueZ state, read-only.
+pub struct OtherLinux {
+    pub source: LinuxSource,
+    /// The running system's state, which is never used as a source.
+    pub running: PathBuf,
+}
+
+impl PairingSource for OtherLinux {
+    fn describe(&self) -> String {
+        match &self.source {
+            LinuxSource::Directory(path) => path.display().to_string(),
+            LinuxSource::Partition(device) => device.clone(),
+            LinuxSource::Discover => "any other Linux installation".to_string(),
+        }
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        read_linux_devices(&self.source, &self.running)
+    }
+}
+
+/// A BlueZ state directory. Unlike `OtherLinux`, this may be the running system's own,
+/// for example to export it.
+pub struct BluezTree {
+    pub path: PathBuf,
+}
+
+impl PairingSource for BluezTree {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        parse_bluez(&self.path.display().to_string(), &self.path)
+    }
+}
+
+impl PairingSink for BluezTree {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+        let changed = apply_to_bluez(&self.path, bt_device_info, options)?;
+        // Another installation's directory is not what the running bluetoothd uses.
+        if changed && is_same_directory(&self.path, Path::new("/var/lib/bluetooth")) {
+            restart_bluetooth_service();
+        }
+        Ok(changed)
+    }
+}
@@ -0,0 +1,410 @@
+use std::{collections::HashMap, fs, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+use anyhow::{bail, Context, Result};
+use term_ansi::*;
+
+use crate::{
+    bluetooth::{print_devices, BtDeviceInfo, DeviceMap, PairingType},
+    regfile::{hex_upper, le_number},
+    store::PairingSource,
+};
+
+const BTSNOOP_MAGIC: &[u8; 8] = b"btsnoop\0";
+const HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 24;
+
+/// Datalink types: H1 keeps the packet type in the record flags, H4 (Android) in the first
+/// byte, and the Linux monitor format (`btmon -w`) in the flags along with the adapter index.
+const DATALINK_H1: u32 = 1001;
+const DATALINK_H4: u32 = 1002;
+const DATALINK_MONITOR: u32 = 2001;
+
+/// Microseconds between 0000-01-01, where btsnoop timestamps start, and the Unix epoch.
+const BTSNOOP_EPOCH_OFFSET: u64 = 0x00dc_ddb3_0f2f_8000;
+
+const EVT_DISCONN_COMPLETE: u8 = 0x05;
+const EVT_REMOTE_NAME_REQ_COMPLETE: u8 = 0x07;
+const EVT_CMD_COMPLETE: u8 = 0x0e;
+const EVT_LINK_KEY_NOTIFY: u8 = 0x18;
+const EVT_LE_META: u8 = 0x3e;
+
+const LE_CONN_COMPLETE: u8 = 0x01;
+const LE_LTK_REQUEST: u8 = 0x05;
+const LE_ENHANCED_CONN_COMPLETE: u8 = 0x0a;
+const LE_ENHANCED_CONN_COMPLETE_V2: u8 = 0x29;
+
+const OP_LINK_KEY_REPLY: u16 = 0x040b;
+const OP_READ_BD_ADDR: u16 = 0x1009;
+const OP_LE_START_ENCRYPTION: u16 = 0x2019;
+const OP_LE_LTK_REQUEST_REPLY: u16 = 0x201a;
+
+const L2CAP_CID_SMP: u16 = 0x0006;
+
+const SMP_PAIRING_REQUEST: u8 = 0x01;
+const SMP_PAIRING_RESPONSE: u8 = 0x02;
+const SMP_ENCRYPT_INFO: u8 = 0x06;
+const SMP_CENTRAL_IDENT: u8 = 0x07;
+const SMP_IDENT_INFO: u8 = 0x08;
+const SMP_IDENT_ADDR_INFO: u8 = 0x09;
+/// `AuthReq` flag both sides set for LE Secure Connections.
+const SMP_AUTH_SC: u8 = 0x08;
+
+enum Packet<'a> {
+    Command(&'a [u8]),
+    Event(&'a [u8]),
+    Acl { received: bool, data: &'a [u8] },
+    /// A monitor `New Index` record, announcing an adapter and its address.
+    NewIndex(&'a [u8]),
+    Other,
+}
+
+struct Record<'a> {
+    /// Adapter the packet belongs to; always 0 outside the monitor format.
+    index: u16,
+    time: Option<SystemTime>,
+    packet: Packet<'a>,
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+/// HCI sends addresses least significant byte first.
+fn bdaddr(bytes: &[u8]) -> String {
+    bytes[..6].iter().rev().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(":")
+}
+
+fn address_type(kind: u8) -> &'static str {
+    // 0 and 2 are public, 1 and 3 random; 2 and 3 mean the controller resolved the address.
+    if kind & 1 == 0 { "public" } else { "static" }
+}
+
+fn packet(datalink: u32, flags: u32, data: &[u8]) -> (u16, Packet<'_>) {
+    let received = flags & 1 != 0;
+    match datalink {
+        DATALINK_H4 => match data.split_first() {
+            Some((0x01, rest)) => (0, Packet::Command(rest)),
+            Some((0x02, rest)) => (0, Packet::Acl { received, data: rest }),
+            Some((0x04, rest)) => (0, Packet::Event(rest)),
+            _ => (0, Packet::Other),
+        },
+        DATALINK_H1 if flags & 2 != 0 => (0, if received { Packet::Event(data) } else { Packet::Command(data) }),
+        DATALINK_H1 => (0, Packet::Acl { received, data }),
+        _ => ((flags >> 16) as u16, match flags & 0xffff {
+            0 => Packet::NewIndex(data),
+            2 => Packet::Command(data),
+            3 => Packet::Event(data),
+            4 => Packet::Acl { received: false, data },
+            5 => Packet::Acl { received: true, data },
+            _ => Packet::Other,
+        }),
+    }
+}
+
+fn records(bytes: &[u8]) -> Result<Vec<Record<'_>>> {
+    if bytes.len() < HEADER_LEN || &bytes[..8] != BTSNOOP_MAGIC {
+        bail!("Not a btsnoop capture");
+    }
+    let datalink = be32(&bytes[12..]);
+    if ![DATALINK_H1, DATALINK_H4, DATALINK_MONITOR].contains(&datalink) {
+        bail!("btsnoop datalink type {} is not supported", datalink);
+    }
+
+    let mut records = Vec::new();
+    let mut offset = HEADER_LEN;
+    while offset + RECORD_HEADER_LEN <= bytes.len() {
+        let header = &bytes[offset..offset + RECORD_HEADER_LEN];
+        let included = be32(&header[4..]) as usize;
+        let flags = be32(&header[8..]);
+        let timestamp = u64::from_be_bytes(header[16..24].try_into().unwrap());
+        offset += RECORD_HEADER_LEN;
+        let Some(data) = bytes.get(offset..offset + included) else {
+            eprintln!("{}", yellow!("Warning: the capture ends in the middle of a packet"));
+            break;
+        };
+        offset += included;
+
+        let (index, packet) = packet(datalink, flags, data);
+        let time = timestamp.checked_sub(BTSNOOP_EPOCH_OFFSET).map(|micros| UNIX_EPOCH + Duration::from_micros(micros));
+        records.push(Record { index, time, packet });
+    }
+    Ok(records)
+}
+
+/// An LE connection, whose keys are only tied to an address once it ends.
+#[derive(Default)]
+struct Connection {
+    address: String,
+    address_type: String,
+    /// Identity address the device distributed, in place of a resolvable private one.
+    identity: Option<(String, String)>,
+    info: BtDeviceInfo,
+    pairing_request: Option<(u8, u8)>,
+    pairing: Option<PairingType>,
+    /// Rand and EDIV of the controller's last LTK request, when the device is central.
+    ltk_request: Option<(u64, u64)>,
+    /// L2CAP frames being reassembled, sent and received.
+    fragments: [Vec<u8>; 2],
+}
+
+impl Connection {
+    /// Keeps an LTK the link is encrypted with. As peripheral, a key with a Rand or EDIV is
+    /// the one the local adapter distributed in a legacy pairing.
+    fn encryption_key(&mut self, time: Option<SystemTime>, rand: u64, ediv: u64, ltk: &[u8], central: bool) {
+        // During a legacy pairing the first key is the short-term key.
+        if rand == 0 && ediv == 0 && self.pairing == Some(PairingType::Legacy) {
+            return;
+        }
+        let info = &mut self.info;
+        if central || (rand == 0 && ediv == 0) {
+            (info.ltk, info.erand, info.ediv) = (hex_upper(ltk), rand.to_string(), ediv.to_string());
+        } else {
+            (info.peripheral_ltk, info.peripheral_erand, info.peripheral_ediv) = (hex_upper(ltk), rand.to_string(), ediv.to_string());
+        }
+        info.last_written = time;
+    }
+}
+
+#[derive(Default)]
+struct Capture {
+    adapters: HashMap<u16, String>,
+    local_irks: HashMap<u16, String>,
+    connections: HashMap<(u16, u16), Connection>,
+    devices: HashMap<(u16, String), BtDeviceInfo>,
+    names: HashMap<String, String>,
+}
+
+impl Capture {
+    fn device(&mut self, index: u16, mac: &str) -> &mut BtDeviceInfo {
+        self.devices.entry((index, mac.to_string())).or_insert_with(|| BtDeviceInfo {
+            mac: mac.to_string(),
+            address_type: "public".to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn event(&mut self, index: u16, time: Option<SystemTime>, data: &[u8]) {
+        let Some((&code, rest)) = data.split_first() else { return };
+        let params = rest.get(1..).unwrap_or_default();
+
+        match code {
+            EVT_LINK_KEY_NOTIFY if params.len() >= 22 => {
+                let device = self.device(index, &bdaddr(params));
+                device.link_key = hex_upper(&params[6..22]);
+                if let Some(key_type) = params.get(22) {
+                    device.link_key_type = key_type.to_string();
+                }
+                device.last_written = time;
+            }
+            EVT_REMOTE_NAME_REQ_COMPLETE if params.len() >= 7 && params[0] == 0 => {
+                let name = &params[7..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                if !name.is_empty() {
+                    self.names.insert(bdaddr(&params[1..]), String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            EVT_CMD_COMPLETE if params.len() >= 10 && le16(&params[1..]) == OP_READ_BD_ADDR && params[3] == 0 => {
+                self.adapters.insert(index, bdaddr(&params[4..]));
+            }
+            EVT_DISCONN_COMPLETE if params.len() >= 3 && params[0] == 0 => {
+                if let Some(connection) = self.connections.remove(&(index, le16(&params[1..]) & 0x0fff)) {
+                    self.finish(index, connection);
+                }
+            }
+            EVT_LE_META => match params.split_first() {
+                Some((&(LE_CONN_COMPLETE | LE_ENHANCED_CONN_COMPLETE | LE_ENHANCED_CONN_COMPLETE_V2), params)) if params.len() >= 11 && params[0] == 0 => {
+                    let connection = Connection {
+                        address: bdaddr(&params[5..]),
+                        address_type: address_type(params[4]).to_string(),
+                        ..Default::default()
+                    };
+                    if let Some(previous) = self.connections.insert((index, le16(&params[1..]) & 0x0fff), connection) {
+                        self.finish(index, previous);
+                    }
+                }
+                Some((&LE_LTK_REQUEST, params)) if params.len() >= 12 => {
+                    if let Some(connection) = self.connections.get_mut(&(index, le16(params) & 0x0fff)) {
+                        connection.ltk_request = Some((le_number(&params[2..10]), le_number(&params[10..12])));
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn command(&mut self, index: u16, time: Option<SystemTime>, data: &[u8]) {
+        if data.len() < 3 {
+            return;
+        }
+        let params = &data[3..];
+
+        match le16(data) {
+            OP_LINK_KEY_REPLY if params.len() >= 22 => {
+                let device = self.device(index, &bdaddr(params));
+                device.link_key = hex_upper(&params[6..22]);
+                device.last_written = device.last_written.or(time);
+            }
+            // The host hands the controller the LTK whenever it encrypts a link, as central or
+            // in reply to the controller's request as peripheral, which is the only place a
+            // Secure Connections LTK shows up.
+            OP_LE_START_ENCRYPTION if params.len() >= 28 => {
+                let Some(connection) = self.connections.get_mut(&(index, le16(params) & 0x0fff)) else { return };
+                let (rand, ediv) = (le_number(&params[2..10]), le_number(&params[10..12]));
+                connection.encryption_key(time, rand, ediv, &params[12..28], true);
+            }
+            OP_LE_LTK_REQUEST_REPLY if params.len() >= 18 => {
+                let Some(connection) = self.connections.get_mut(&(index, le16(params) & 0x0fff)) else { return };
+                let Some((rand, ediv)) = connection.ltk_request.take() else { return };
+                connection.encryption_key(time, rand, ediv, &params[2..18], false);
+            }
+            _ => {}
+        }
+    }
+
+    fn acl(&mut self, index: u16, time: Option<SystemTime>, received: bool, data: &[u8]) {
+        if data.len() < 4 {
+            return;
+        }
+        let handle = le16(data) & 0x0fff;
+        let continuation = (le16(data) >> 12) & 0x3 == 0x1;
+        let Some(connection) = self.connections.get_mut(&(index, handle)) else { return };
+
+        let fragments = &mut connection.fragments[usize::from(received)];
+        if !continuation {
+            fragments.clear();
+        }
+        fragments.extend_from_slice(&data[4..]);
+        if fragments.len() < 4 || fragments.len() < 4 + usize::from(le16(fragments)) {
+            return;
+        }
+        let frame = std::mem::take(fragments);
+        if le16(&frame[2..]) == L2CAP_CID_SMP {
+            smp(connection, time, received, &frame[4..4 + usize::from(le16(&frame))]);
+        }
+    }
+
+    /// Files the keys of an ended connection under the device's identity address.
+    fn finish(&mut self, index: u16, connection: Connection) {
+        let info = connection.info;
+        if !info.local_irk.is_empty() {
+            self.local_irks.insert(index, info.local_irk.clone());
+        }
+        if info.ltk.is_empty() && info.peripheral_ltk.is_empty() && info.irk.is_empty() {
+            return;
+        }
+        let (mac, address_type) = connection.identity.unwrap_or((connection.address, connection.address_type));
+
+        let device = self.device(index, &mac);
+        device.address_type = address_type;
+        for (field, value) in [
+            (&mut device.ltk, info.ltk), (&mut device.erand, info.erand), (&mut device.ediv, info.ediv),
+            (&mut device.irk, info.irk), (&mut device.enc_size, info.enc_size),
+            (&mut device.peripheral_ltk, info.peripheral_ltk), (&mut device.peripheral_erand, info.peripheral_erand),
+            (&mut device.peripheral_ediv, info.peripheral_ediv),
+        ] {
+            if !value.is_empty() {
+                *field = value;
+            }
+        }
+        device.last_written = info.last_written.or(device.last_written);
+    }
+}
+
+/// Keys the device sent are its own; keys sent to it are the ones the local adapter distributed.
+fn smp(connection: &mut Connection, time: Option<SystemTime>, received: bool, pdu: &[u8]) {
+    let Some((&code, params)) = pdu.split_first() else { return };
+    let info = &mut connection.info;
+
+    match code {
+        SMP_PAIRING_REQUEST if params.len() >= 6 => connection.pairing_request = Some((params[2], params[3])),
+        SMP_PAIRING_RESPONSE if params.len() >= 6 => {
+            let Some((auth, max_key_size)) = connection.pairing_request else { return };
+            connection.pairing = Some(if auth & params[2] & SMP_AUTH_SC != 0 { PairingType::SecureConnections } else { PairingType::Legacy });
+            info.enc_size = max_key_size.min(params[3]).to_string();
+        }
+        SMP_ENCRYPT_INFO if params.len() >= 16 => {
+            if received { info.ltk = hex_upper(&params[..16]) } else { info.peripheral_ltk = hex_upper(&params[..16]) }
+            info.last_written = time;
+        }
+        SMP_CENTRAL_IDENT if params.len() >= 10 => {
+            let (ediv, rand) = (le_number(&params[..2]).to_string(), le_number(&params[2..10]).to_string());
+            if received { (info.ediv, info.erand) = (ediv, rand) } else { (info.peripheral_ediv, info.peripheral_erand) = (ediv, rand) }
+        }
+        SMP_IDENT_INFO if params.len() >= 16 => {
+            if received { info.irk = hex_upper(&params[..16]) } else { info.local_irk = hex_upper(&params[..16]) }
+        }
+        SMP_IDENT_ADDR_INFO if received && params.len() >= 7 => {
+            connection.identity = Some((bdaddr(&params[1..]), address_type(params[0]).to_string()));
+        }
+        _ => {}
+    }
+}
+
+/// Recovers pairings from a btsnoop capture taken while they were made: link keys from
+/// HCI Link Key Notification events with their key type, LE keys from the SMP key
+/// distribution and the LTKs the host passes to the controller. Devices are named where the capture has a
+/// remote name request, otherwise after their address.
+pub fn parse_btsnoop(bytes: &[u8]) -> Result<DeviceMap> {
+    let mut capture = Capture::default();
+
+    for record in records(bytes)? {
+        match record.packet {
+            Packet::Event(data) => capture.event(record.index, record.time, data),
+            Packet::Command(data) => capture.command(record.index, record.time, data),
+            Packet::Acl { received, data } => capture.acl(record.index, record.time, received, data),
+            Packet::NewIndex(data) if data.len() >= 8 => { capture.adapters.insert(record.index, bdaddr(&data[2..])); }
+            _ => {}
+        }
+    }
+    for ((index, _), connection) in std::mem::take(&mut capture.connections) {
+        capture.finish(index, connection);
+    }
+
+    let mut devices: Vec<((u16, String), BtDeviceInfo)> = capture.devices.into_iter().collect();
+    devices.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut bt_device_info = DeviceMap::new();
+    for ((index, mac), mut info) in devices {
+        if info.ltk.is_empty() && info.link_key.is_empty() {
+            continue;
+        }
+        if info.ltk.is_empty() {
+            info.address_type = "public".to_string();
+        } else if info.enc_size.is_empty() {
+            info.enc_size = "16".to_string();
+        }
+        info.adapter = capture.adapters.get(&index).cloned().unwrap_or_default();
+        info.local_irk = capture.local_irks.get(&index).cloned().unwrap_or_default();
+
+        info.name = capture.names.get(&mac).cloned().unwrap_or(mac);
+        bt_device_info.insert(info.key(), info);
+    }
+    Ok(bt_device_info)
+}
+
+/// A btsnoop capture from Android or `btmon -w`, read-only.
+pub struct BtsnoopFile {
+    pub path: PathBuf,
+}
+
+impl PairingSource for BtsnoopFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let bytes = fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let mut bt_device_info = parse_btsnoop(&bytes)?;
+
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        print_devices("captured", &self.path.display().to_string(), &bt_device_info);
+
+        Ok(bt_device_info)
+    }
+}
@@ -0,0 +1,223 @@
+use std::{fs, path::PathBuf};
+use anyhow::{bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{aead::{Aead, KeyInit, Payload}, XChaCha20Poly1305, XNonce};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use term_ansi::*;
+
+use crate::{
+    bluetooth::{device_map, BtDeviceInfo, DeviceMap, SyncOptions},
+    store::{map_adapters, PairingSink, PairingSource},
+    utils::write_atomic,
+};
+
+/// Bumped whenever a field changes meaning; newer bundles are refused.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Start of an encrypted bundle; the last byte is the container version.
+const SEALED_MAGIC: &[u8; 4] = b"BTK\x01";
+/// Argon2 algorithm byte and the memory, time and parallelism costs, each a little-endian u32.
+const KDF_PARAMS_LEN: usize = 13;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// Memory cost, in KiB, above which a bundle is refused rather than exhausting memory.
+const MAX_M_COST: u32 = 1024 * 1024;
+
+/// Where the passphrase is taken from when not typed in.
+pub const PASSPHRASE_ENV: &str = "BT_SYNC_PASSPHRASE";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    version: u32,
+    devices: Vec<BtDeviceInfo>,
+}
+
+/// Serializes the device map, sorted by name so the output diffs cleanly.
+pub fn to_json(bt_device_info: &DeviceMap) -> Result<String> {
+    let mut devices: Vec<BtDeviceInfo> = bt_device_info.values().cloned().collect();
+    devices.sort_by(|a, b| (&a.name, &a.adapter, &a.mac).cmp(&(&b.name, &b.adapter, &b.mac)));
+
+    let mut json = serde_json::to_string_pretty(&Bundle { version: BUNDLE_VERSION, devices })?;
+    json.push('\n');
+    Ok(json)
+}
+
+pub fn from_json(json: &str) -> Result<DeviceMap> {
+    let bundle: Bundle = serde_json::from_str(json).context("Failed to parse pairing bundle")?;
+    if bundle.version > BUNDLE_VERSION {
+        bail!("Pairing bundle version {} is newer than this bt-sync supports ({})", bundle.version, BUNDLE_VERSION);
+    }
+    Ok(device_map(bundle.devices))
+}
+
+/// Pairings as a JSON document.
+pub struct JsonFile {
+    pub path: PathBuf,
+}
+
+impl PairingSource for JsonFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let json = fs::read_to_string(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let mut bt_device_info = from_json(&json)?;
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        Ok(bt_device_info)
+    }
+}
+
+impl PairingSink for JsonFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+        let bt_device_info = map_adapters(bt_device_info, &[], options);
+        write_atomic(&self.path, to_json(&bt_device_info)?)?;
+        println!("{}", green!("\n=== {} device(s) written to {} ===", bt_device_info.len(), self.path.display()));
+        Ok(true)
+    }
+}
+
+fn kdf_params_bytes(algorithm: Algorithm, params: &Params) -> Vec<u8> {
+    let mut bytes = vec![algorithm as u8];
+    for cost in [params.m_cost(), params.t_cost(), params.p_cost()] {
+        bytes.extend_from_slice(&cost.to_le_bytes());
+    }
+    bytes
+}
+
+fn parse_kdf_params(bytes: &[u8]) -> Result<(Algorithm, Params)> {
+    let algorithm = match bytes[0] {
+        0 => Algorithm::Argon2d,
+        1 => Algorithm::Argon2i,
+        2 => Algorithm::Argon2id,
+        other => bail!("Unknown bundle key derivation {}", other),
+    };
+    let cost = |index: usize| u32::from_le_bytes(bytes[1 + index * 4..5 + index * 4].try_into().unwrap());
+    if cost(0) > MAX_M_COST {
+        bail!("Bundle asks for {} KiB of memory to derive its key, at most {} are allowed", cost(0), MAX_M_COST);
+    }
+    let params = Params::new(cost(0), cost(1), cost(2), None)
+        .map_err(|e| anyhow::anyhow!("Invalid bundle key derivation parameters: {}", e))?;
+    Ok((algorithm, params))
+}
+
+fn bundle_key(passphrase: &str, salt: &[u8], algorithm: Algorithm, params: Params) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::new(algorithm, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive bundle key: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypts a JSON bundle: magic, Argon2 algorithm and costs, salt, nonce, then
+/// XChaCha20-Poly1305 ciphertext. Everything before the nonce is authenticated along
+/// with the contents.
+pub fn seal(json: &str, passphrase: &str) -> Result<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let salt: [u8; SALT_LEN] = rng.gen();
+    let nonce: [u8; NONCE_LEN] = rng.gen();
+    let (algorithm, params) = (Algorithm::Argon2id, Params::default());
+
+    let mut sealed = SEALED_MAGIC.to_vec();
+    sealed.extend(kdf_params_bytes(algorithm, &params));
+    sealed.extend_from_slice(&salt);
+    let cipher = XChaCha20Poly1305::new(&bundle_key(passphrase, &salt, algorithm, params)?.into());
+    let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: json.as_bytes(), aad: &sealed })
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt bundle"))?;
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub fn unseal(sealed: &[u8], passphrase: &str) -> Result<String> {
+    if sealed.len() < 4 || &sealed[..3] != b"BTK" {
+        bail!("Not a bt-sync bundle");
+    }
+    if sealed[3] != SEALED_MAGIC[3] {
+        bail!("Bundle container version {} is not supported", sealed[3]);
+    }
+    let header_len = SEALED_MAGIC.len() + KDF_PARAMS_LEN + SALT_LEN;
+    if sealed.len() < header_len + NONCE_LEN {
+        bail!("Bundle is truncated");
+    }
+
+    let (header, rest) = sealed.split_at(header_len);
+    let (kdf_params, salt) = header[SEALED_MAGIC.len()..].split_at(KDF_PARAMS_LEN);
+    let (algorithm, params) = parse_kdf_params(kdf_params)?;
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let cipher = XChaCha20Poly1305::new(&bundle_key(passphrase, salt, algorithm, params)?.into());
+    let json = cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the bundle is damaged"))?;
+    String::from_utf8(json).context("Bundle does not contain text")
+}
+
+/// The passphrase from `BT_SYNC_PASSPHRASE`, or typed in without echo, twice when `confirm` is set.
+fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if passphrase.is_empty() {
+            bail!("{} is set but empty", PASSPHRASE_ENV);
+        }
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("Bundle passphrase: ")?;
+    if passphrase.is_empty() {
+        bail!("The bundle passphrase must not be empty");
+    }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+/// A passphrase-encrypted JSON bundle, for carrying pairings to another machine.
+pub struct EncryptedBundle {
+    pub path: PathBuf,
+    /// Asked for when needed if not set.
+    pub passphrase: Option<String>,
+}
+
+impl EncryptedBundle {
+    fn passphrase(&self, confirm: bool) -> Result<String> {
+        match &self.passphrase {
+            Some(passphrase) => Ok(passphrase.clone()),
+            None => read_passphrase(confirm),
+        }
+    }
+}
+
+impl PairingSource for EncryptedBundle {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let sealed = fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let mut bt_device_info = from_json(&unseal(&sealed, &self.passphrase(false)?)?)?;
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        Ok(bt_device_info)
+    }
+}
+
+impl PairingSink for EncryptedBundle {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+        let bt_device_info = map_adapters(bt_device_info, &[], options);
+        let sealed = seal(&to_json(&bt_device_info)?, &self.passphrase(true)?)?;
+        write_atomic(&self.path, sealed)?;
+        println!("{}", green!("\n=== {} device(s) written to bundle {} ===", bt_device_info.len(), self.path.display()));
+        Ok(true)
+    }
+}
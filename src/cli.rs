@@ -0,0 +1,260 @@
+use anyhow::{bail, Context, Result};
+
+use std::path::PathBuf;
+
+use crate::{bluetooth::{normalize_mac, CollisionPolicy, MigrateOptions, SyncDirection, SyncOptions}, bluez::LinuxSource, inspect::{InspectFormat, InspectOptions}, partitions::SourcePolicy, store::StoreSpec};
+
+pub const USAGE: &str = "Usage: bt-sync [COMMAND] [OPTIONS]
+
+Commands:
+  sync      Update Linux pairings that also exist on Windows (default)
+  import    Like sync, and create Linux pairings for Windows-only devices
+  export --bundle <file>
+            Write every pairing into a passphrase-encrypted bundle, to be
+            restored with `import --bundle <file>` on another machine
+  migrate-adapter --from <mac> --to <mac> [--reg <file>]
+            Move all pairings of a replaced adapter to the new one, and
+            optionally write the matching Windows changes to a .reg file
+  status    Show which devices differ between Windows and Linux without
+            changing anything; exits with 3 if any device is out of sync
+  doctor    Explain for every device why a sync does or does not update it
+  inspect --hive <file> [--format <tree|json>]
+            Print the BTHPORT\\Parameters registry subtree of a SYSTEM
+            hive, decoding the fields bt-sync uses
+  help      Show this message
+
+Options:
+  --adapter-map <windows>=<linux>
+            Apply keys of a Windows adapter to a differently named
+            Linux adapter directory (may be repeated)
+  --on-collision <merge|skip|replace>
+            What to do when a device is renamed to an address that
+            already has a pairing directory (default: merge)
+  --direction <windows|auto>
+            windows: Windows pairings always replace Linux ones (default)
+            auto: per device, keep whichever side was paired last
+  --reg <file>
+            With --direction auto, write Windows registry changes for
+            devices that were paired on Linux last
+  --aggregate <newest|first|/dev/...>
+            Read every Windows install instead of only the first, and
+            report devices whose keys differ between them; the value
+            picks the install whose key is used for those devices
+  --from-linux <dir|/dev/...|auto>
+            Take pairings from another Linux installation instead of
+            Windows: a /var/lib/bluetooth style directory, an ext4 or
+            btrfs partition, or auto to use the first one found
+  --source <hive|reg|bluez|json|bundle|plist|android|btsnoop>:<path>
+            Read pairings from a SYSTEM hive file, a .reg file, a BlueZ
+            state directory, a JSON file, an encrypted bundle, macOS's
+            com.apple.bluetoothd.plist, Android's bt_config.conf or a
+            btsnoop capture instead of Windows
+  --sink <hive|reg|bluez|json|bundle|android>:<path>
+            Write pairings there instead of /var/lib/bluetooth; a hive
+            is changed in place, so only existing pairings are updated,
+            and android writes a fragment to merge into bt_config.conf
+  --btsnoop <file>
+            Same as --source btsnoop:<file>: take the keys from an HCI
+            capture (Android btsnoop_hci.log or btmon -w) of the pairing
+  --bundle <file>
+            With import, restore the pairings of an encrypted bundle; the
+            passphrase is read from BT_SYNC_PASSPHRASE or asked for";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Sync(SyncOptions),
+    Status(SyncOptions),
+    Doctor(SyncOptions),
+    Inspect(InspectOptions),
+    MigrateAdapter(MigrateOptions),
+    Help,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command> {
+    let mut args = args.iter().peekable();
+    let mut options = SyncOptions::default();
+    let mut export = false;
+    let mut bundle = None;
+    let mut btsnoop = None;
+
+    match args.peek().map(|arg| arg.as_str()) {
+        Some("sync") => { args.next(); }
+        Some("import") => { args.next(); options.import = true; }
+        Some("export") => { args.next(); export = true; }
+        Some("status") => { args.next(); return parse_report_args(args, Command::Status); }
+        Some("doctor") => { args.next(); return parse_report_args(args, Command::Doctor); }
+        Some("inspect") => { args.next(); return parse_inspect_args(args); }
+        Some("migrate-adapter") => { args.next(); return parse_migrate_args(args); }
+        Some("help") | Some("-h") | Some("--help") => return Ok(Command::Help),
+        Some(arg) if !arg.starts_with('-') => bail!("Unknown command: {}", arg),
+        _ => {}
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--adapter-map" => parse_adapter_map(args.next(), &mut options)?,
+            "--on-collision" => {
+                options.on_collision = match args.next().context("--on-collision needs a value")?.as_str() {
+                    "merge" => CollisionPolicy::Merge,
+                    "skip" => CollisionPolicy::Skip,
+                    "replace" => CollisionPolicy::Replace,
+                    other => bail!("Unknown collision policy: {}", other),
+                };
+            }
+            "--direction" => {
+                options.direction = match args.next().context("--direction needs a value")?.as_str() {
+                    "windows" => SyncDirection::WindowsToLinux,
+                    "auto" => SyncDirection::Auto,
+                    other => bail!("Unknown sync direction: {}", other),
+                };
+            }
+            "--reg" => options.reg = Some(PathBuf::from(args.next().context("--reg needs a value")?)),
+            "--aggregate" => parse_aggregate(args.next(), &mut options)?,
+            "--from-linux" => parse_from_linux(args.next(), &mut options)?,
+            "--source" => options.source = Some(StoreSpec::parse(args.next().context("--source needs a value")?)?),
+            "--sink" => options.sink = match StoreSpec::parse(args.next().context("--sink needs a value")?)? {
+                StoreSpec::Plist(_) => bail!("macOS plists can only be used with --source"),
+                StoreSpec::Btsnoop(_) => bail!("btsnoop captures can only be used with --source"),
+                sink => Some(sink),
+            },
+            "--btsnoop" => btsnoop = Some(PathBuf::from(args.next().context("--btsnoop needs a value")?)),
+            "--bundle" => bundle = Some(PathBuf::from(args.next().context("--bundle needs a value")?)),
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => bail!("Unexpected argument: {}", arg),
+        }
+    }
+
+    options.export = export;
+    match bundle {
+        Some(_) if export && options.sink.is_some() => bail!("export writes to --bundle, --sink cannot be used with it"),
+        Some(path) if export => options.sink = Some(StoreSpec::Bundle(path)),
+        Some(_) if options.import && options.source.is_some() => bail!("import reads --bundle, --source cannot be used with it"),
+        Some(path) if options.import => options.source = Some(StoreSpec::Bundle(path)),
+        Some(_) => bail!("--bundle only works with export and import"),
+        None if export => bail!("export needs --bundle <file>"),
+        None => {}
+    }
+
+    set_btsnoop_source(btsnoop, &mut options)?;
+    check_source(&options)?;
+    // Linux-newer pairings are written back as a .reg file, which only Windows can use.
+    if (options.from_linux.is_some() || options.source.is_some()) && options.direction == SyncDirection::Auto {
+        bail!("--direction auto only works with Windows as the source");
+    }
+    if options.sink.is_some() && options.direction == SyncDirection::Auto {
+        bail!("--direction auto only works with the BlueZ state as the sink");
+    }
+
+    Ok(Command::Sync(options))
+}
+
+fn parse_adapter_map(value: Option<&String>, options: &mut SyncOptions) -> Result<()> {
+    let value = value.context("--adapter-map needs a value")?;
+    let (windows, linux) = value.split_once('=').context("--adapter-map expects <windows>=<linux>")?;
+    options.adapter_map.insert(normalize_mac(windows), normalize_mac(linux));
+    Ok(())
+}
+
+fn parse_aggregate(value: Option<&String>, options: &mut SyncOptions) -> Result<()> {
+    options.aggregate = Some(match value.context("--aggregate needs a value")?.as_str() {
+        "newest" => SourcePolicy::Newest,
+        "first" => SourcePolicy::First,
+        device if device.starts_with("/dev/") => SourcePolicy::Partition(device.to_string()),
+        other => bail!("Unknown aggregate policy: {}", other),
+    });
+    Ok(())
+}
+
+fn parse_from_linux(value: Option<&String>, options: &mut SyncOptions) -> Result<()> {
+    options.from_linux = Some(match value.context("--from-linux needs a value")?.as_str() {
+        "auto" => LinuxSource::Discover,
+        device if device.starts_with("/dev/") => LinuxSource::Partition(device.to_string()),
+        dir => LinuxSource::Directory(PathBuf::from(dir)),
+    });
+    Ok(())
+}
+
+fn set_btsnoop_source(btsnoop: Option<PathBuf>, options: &mut SyncOptions) -> Result<()> {
+    if let Some(path) = btsnoop {
+        if options.source.is_some() {
+            bail!("--btsnoop and --source each choose the source, pass only one");
+        }
+        options.source = Some(StoreSpec::Btsnoop(path));
+    }
+    Ok(())
+}
+
+fn check_source(options: &SyncOptions) -> Result<()> {
+    let sources = [options.aggregate.is_some(), options.from_linux.is_some(), options.source.is_some()];
+    if sources.iter().filter(|&&given| given).count() > 1 {
+        bail!("--aggregate, --from-linux and --source each choose the source, pass only one");
+    }
+    Ok(())
+}
+
+/// Options of the read-only commands, which only choose where pairings are read from.
+fn parse_report_args<'a>(mut args: impl Iterator<Item = &'a String>, command: fn(SyncOptions) -> Command) -> Result<Command> {
+    let mut options = SyncOptions::default();
+    let mut btsnoop = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--adapter-map" => parse_adapter_map(args.next(), &mut options)?,
+            "--aggregate" => parse_aggregate(args.next(), &mut options)?,
+            "--from-linux" => parse_from_linux(args.next(), &mut options)?,
+            "--source" => options.source = Some(StoreSpec::parse(args.next().context("--source needs a value")?)?),
+            "--btsnoop" => btsnoop = Some(PathBuf::from(args.next().context("--btsnoop needs a value")?)),
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => bail!("Unexpected argument: {}", arg),
+        }
+    }
+
+    set_btsnoop_source(btsnoop, &mut options)?;
+    check_source(&options)?;
+    Ok(command(options))
+}
+
+fn parse_inspect_args<'a>(mut args: impl Iterator<Item = &'a String>) -> Result<Command> {
+    let mut options = InspectOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hive" => options.hive = PathBuf::from(args.next().context("--hive needs a value")?),
+            "--format" => {
+                options.format = match args.next().context("--format needs a value")?.as_str() {
+                    "tree" => InspectFormat::Tree,
+                    "json" => InspectFormat::Json,
+                    other => bail!("Unknown format: {}", other),
+                };
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => bail!("Unexpected argument: {}", arg),
+        }
+    }
+
+    if options.hive.as_os_str().is_empty() {
+        bail!("inspect needs --hive <file>");
+    }
+
+    Ok(Command::Inspect(options))
+}
+
+fn parse_migrate_args<'a>(mut args: impl Iterator<Item = &'a String>) -> Result<Command> {
+    let mut options = MigrateOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => options.from = normalize_mac(args.next().context("--from needs a value")?),
+            "--to" => options.to = normalize_mac(args.next().context("--to needs a value")?),
+            "--reg" => options.reg = Some(PathBuf::from(args.next().context("--reg needs a value")?)),
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => bail!("Unexpected argument: {}", arg),
+        }
+    }
+
+    if options.from.is_empty() || options.to.is_empty() {
+        bail!("migrate-adapter needs both --from and --to");
+    }
+
+    Ok(Command::MigrateAdapter(options))
+}
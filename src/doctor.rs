@@ -0,0 +1,172 @@
+use std::path::Path;
+use anyhow::Result;
+use term_ansi::*;
+
+use crate::{
+    bluetooth::{adapter_directories, device_directories, devices_for_adapter, find_windows_device, read_bt_info, read_device_info, read_source_devices, resolve_adapter_map, device_named, DeviceMap, SyncOptions},
+    hive::{diagnose_reg, SkippedKey},
+    partitions::{collect_ntfs_partitions, find_in_ntfs_partitions},
+};
+
+/// One device traced through the filters a sync applies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnosis {
+    /// Device name, or the address when none is known.
+    pub name: String,
+    pub mac: String,
+    /// Whether a sync will update the device.
+    pub ok: bool,
+    pub reason: String,
+    /// What to do about it, empty when the device is fine.
+    pub hint: String,
+}
+
+impl Diagnosis {
+    fn ok(name: &str, mac: &str, reason: &str) -> Diagnosis {
+        Diagnosis { name: name.to_string(), mac: mac.to_string(), ok: true, reason: reason.to_string(), hint: String::new() }
+    }
+
+    fn excluded(name: &str, mac: &str, reason: String, hint: String) -> Diagnosis {
+        Diagnosis { name: name.to_string(), mac: mac.to_string(), ok: false, reason, hint }
+    }
+}
+
+/// Explains for every Windows and Linux pairing whether a sync will pick it up.
+pub fn diagnose(
+    bt_dir_path: &Path,
+    bt_device_info: &DeviceMap,
+    skipped: &[SkippedKey],
+    options: &SyncOptions
+) -> Result<Vec<Diagnosis>, Box<dyn std::error::Error>> {
+    let adapter_paths = adapter_directories(bt_dir_path)?;
+    let linux_adapters: Vec<String> = adapter_paths.iter().map(|(adapter, _)| adapter.clone()).collect();
+    let adapter_map = resolve_adapter_map(bt_device_info, &linux_adapters, options);
+
+    let mut diagnoses = Vec::new();
+    let mut seen_names = Vec::new();
+    let mut seen_macs = Vec::new();
+
+    for (adapter, path) in &adapter_paths {
+        let adapter_info = devices_for_adapter(adapter, bt_device_info, &adapter_map);
+
+        for sub_path in device_directories(path)? {
+            let mac = sub_path.file_name().unwrap().to_string_lossy().into_owned();
+            let windows_by_mac = bt_device_info.values().find(|info| info.mac == mac);
+            let skipped_key = skipped.iter().find(|key| key.mac == mac);
+            seen_macs.push(mac.clone());
+
+            let (name, content) = match read_device_info(&sub_path) {
+                Err(e) => {
+                    diagnoses.push(Diagnosis::excluded(&mac, &mac,
+                        format!("cannot read {}: {}", sub_path.join("info").display(), e),
+                        "run as root, or check the file's permissions".to_string()));
+                    continue;
+                }
+                Ok(None) => {
+                    let hint = match windows_by_mac {
+                        Some(windows) => format!("add Name={} under [General] in its info file", windows.name),
+                        None => "connect the device once on Linux so BlueZ stores its name".to_string(),
+                    };
+                    diagnoses.push(Diagnosis::excluded(&mac, &mac, "info has no Name= under [General]".to_string(), hint));
+                    continue;
+                }
+                Ok(Some(found)) => found,
+            };
+            seen_names.push(name.clone());
+
+            let diagnosis = match find_windows_device(&adapter_info, &name, &mac) {
+                Some(windows) => {
+                    let linux = read_bt_info(&content);
+                    if windows.mac == mac && windows.display_key().eq_ignore_ascii_case(linux.display_key()) {
+                        Diagnosis::ok(&name, &mac, "matched, already in sync")
+                    } else {
+                        Diagnosis::ok(&name, &mac, "matched, will be updated")
+                    }
+                }
+                None => match (device_named(bt_device_info, &name), skipped_key, windows_by_mac) {
+                    (Some(windows), _, _) => Diagnosis::excluded(&name, &mac,
+                        format!("Windows pairs it with adapter {}, which is not mapped to {}", windows.adapter, adapter),
+                        format!("pass --adapter-map {}={}", windows.adapter, adapter)),
+                    (None, Some(key), _) => Diagnosis::excluded(&name, &mac,
+                        format!("the Windows key for this address was skipped: {}", key.reason),
+                        key.hint.clone()),
+                    (None, None, Some(windows)) => Diagnosis::excluded(&name, &mac,
+                        format!("named {} on Linux but {} on Windows", name, windows.name),
+                        format!("set Name={} under [General] in its info file", windows.name)),
+                    (None, None, None) => Diagnosis::excluded(&name, &mac,
+                        format!("no Windows pairing named {}", name),
+                        "pair it on Windows first, Linux-only devices are left alone".to_string()),
+                },
+            };
+            diagnoses.push(diagnosis);
+        }
+    }
+
+    for info in bt_device_info.values() {
+        if !seen_names.contains(&info.name) && !seen_macs.contains(&info.mac) {
+            diagnoses.push(Diagnosis::excluded(&info.name, &info.mac,
+                format!("no Linux pairing named {}", info.name),
+                "run `bt-sync import` to create it".to_string()));
+        }
+    }
+
+    for key in skipped {
+        if !seen_macs.contains(&key.mac) {
+            diagnoses.push(Diagnosis::excluded(&key.mac, &key.mac,
+                format!("Windows key on adapter {} was skipped: {}", key.adapter, key.reason),
+                key.hint.clone()));
+        }
+    }
+
+    diagnoses.sort_by(|a, b| (a.ok, &a.name).cmp(&(b.ok, &b.name)));
+    Ok(diagnoses)
+}
+
+fn print_diagnoses(diagnoses: &[Diagnosis]) {
+    println!("{}", green!("\n=== Bluetooth pairing diagnosis ==="));
+
+    println!("{} |      {} |      {}",
+        blue!("{:<30}", "Device Name"),
+        blue!("{:<24}", "Address"),
+        blue!("{:<40}", "Result"));
+    println!("{}", "-".repeat(102));
+
+    for diagnosis in diagnoses {
+        let reason = if diagnosis.ok {
+            green!("{}", diagnosis.reason)
+        } else {
+            yellow!("{}", diagnosis.reason)
+        };
+        println!("{} |      {} |      {}",
+            rgb!(0xf0, 0x00, 0x56, "{:<30}", diagnosis.name),
+            rgb!(0xaa, 0x96, 0xda, "{:<24}", diagnosis.mac),
+            reason);
+
+        if !diagnosis.hint.is_empty() {
+            println!("{} |      {} |  FIX {}", rgb!(0xf0, 0x00, 0x56, "{:<30}", " "), " ".repeat(24), rgb!(0x00, 0xe0, 0x79, "{}", diagnosis.hint));
+        }
+    }
+}
+
+pub fn process_doctor(bt_dir_path: &str, options: &SyncOptions) -> Result<()> {
+    let (bt_device_info, skipped) = if options.from_linux.is_some() || options.source.is_some() {
+        (read_source_devices(bt_dir_path, options)?, Vec::new())
+    } else {
+        let found = match &options.aggregate {
+            Some(policy) => Some(collect_ntfs_partitions(policy)?).filter(|(info, skipped)| !info.is_empty() || !skipped.is_empty()),
+            None => find_in_ntfs_partitions(|_, mountpoint| {
+                Ok(diagnose_reg(mountpoint)?.filter(|(info, skipped)| !info.is_empty() || !skipped.is_empty()))
+            })?,
+        };
+        if found.is_none() {
+            eprintln!("{}", yellow!("No Windows installation with Bluetooth pairings found on any NTFS partition"));
+        }
+        found.unwrap_or_default()
+    };
+
+    let diagnoses = diagnose(Path::new(bt_dir_path), &bt_device_info, &skipped, options)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", bt_dir_path, e))?;
+    print_diagnoses(&diagnoses);
+
+    Ok(())
+}
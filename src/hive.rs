@@ -1,24 +1,175 @@
-use std::{collections::HashMap, fs, io::Read};
-use anyhow::{Context, Result};
-use nt_hive::{Hive, KeyValueData};
-use term_ansi::{blue, green, red, rgb};
+use std::{collections::{hash_map::Entry, HashMap}, fs, io::Read, ops::{Deref, Range}, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+use anyhow::{bail, Context, Result};
+use nt_hive::{Hive, KeyNode, KeyValueData};
+use term_ansi::{green, rgb, yellow};
 use zerocopy::ByteSlice;
 
-use crate::bluetooth::{fmt_mac, BtDeviceInfo};
+use crate::{
+    bluetooth::{fmt_mac, print_devices, BtDeviceInfo, DeviceMap, SyncOptions},
+    regfile::{hex_bytes, reg_name},
+    store::{map_adapters, PairingSink, PairingSource},
+    utils::write_atomic,
+};
 
+const BTHPORT_KEYS: &str = r"ControlSet001\Services\BTHPORT\Parameters\Keys";
+
+/// Transaction log entries start after the log's 512 byte base block.
+const LOG_ENTRIES_OFFSET: usize = 512;
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+}
+
+/// Hex of a little-endian key, in the order BlueZ writes it.
+fn hex_reversed(data: &[u8]) -> String {
+    data.iter().rev().map(|b| format!("{:02X}", b)).collect::<String>()
+}
+
+/// Raw bytes of a value, whether it is stored in one cell or as big data.
+fn value_bytes<H: Deref<Target = Hive<B>>, B: ByteSlice>(key: &KeyNode<H, B>, name: &str) -> Result<Option<Vec<u8>>> {
+    match key.value(name) {
+        Some(val) => {
+            let val = val.with_context(|| format!("Failed to get {}", name))?;
+            let data = val.data().and_then(|data| data.into_vec()).with_context(|| format!("Failed to read {} data", name))?;
+            Ok(Some(data))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A DWORD or QWORD value. Only the size is checked, Windows has written both for some fields.
+fn value_number<H: Deref<Target = Hive<B>>, B: ByteSlice>(key: &KeyNode<H, B>, name: &str) -> Result<Option<u64>> {
+    match value_bytes(key, name)? {
+        Some(data) => match data.len() {
+            4 => Ok(Some(u32::from_le_bytes([data[0], data[1], data[2], data[3]]).into())),
+            8 => Ok(Some(u64::from_le_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]]))),
+            len => anyhow::bail!("{} has {} bytes, expected 4 or 8", name, len),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Device names are NUL-terminated UTF-8; anything else is decoded lossily with a warning.
+pub fn decode_name(data: &[u8], mac: &str) -> String {
+    let data = &data[..data.iter().position(|&b| b == 0).unwrap_or(data.len())];
+    match std::str::from_utf8(data) {
+        Ok(name) => name.to_string(),
+        Err(_) => {
+            let name = String::from_utf8_lossy(data).into_owned();
+            eprintln!("{}", yellow!("Warning: the name of {} is not valid UTF-8, using {:?}", mac, name));
+            name
+        }
+    }
+}
+
+/// REG_SZ data, NUL-terminated UTF-16LE.
+fn decode_utf16(data: &[u8]) -> String {
+    let units = data.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).take_while(|&unit| unit != 0);
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+/// Formats a binary GUID the way registry key names spell it.
+fn guid_string(data: &[u8]) -> Option<String> {
+    let data: [u8; 16] = data.get(..16)?.try_into().ok()?;
+    Some(format!("{{{:08x}-{:04x}-{:04x}-{}-{}}}",
+        u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+        u16::from_le_bytes([data[4], data[5]]),
+        u16::from_le_bytes([data[6], data[7]]),
+        hex(&data[8..10]).to_lowercase(),
+        hex(&data[10..]).to_lowercase()))
+}
+
+/// Names and container ids from `Enum\BTHLE` or `Enum\BTHENUM`, whose device nodes
+/// are called `Dev_<mac>` and hold one instance subkey each.
+fn get_enum_names<B: ByteSlice>(
+    hive: &Hive<B>,
+    bus: &str,
+    bt_name_map: &mut HashMap<String, String>,
+    containers: &mut HashMap<String, String>
+) -> Result<()> {
+    let root = hive.root_key_node()?;
+    let Some(keys) = root.subpath(&format!(r"ControlSet001\Enum\{}", bus)) else { return Ok(()) };
+    let keys = keys?;
+    let Some(devices) = keys.subkeys() else { return Ok(()) };
+
+    for device in devices.context("Failed to get subkeys")? {
+        let device = device.context("Failed to enumerate key")?;
+        let name = device.name().context("Failed to get name")?.to_string().to_lowercase();
+        let Some(mac) = name.strip_prefix("dev_").filter(|mac| mac.len() == 12 && mac.chars().all(|c| c.is_ascii_hexdigit())) else { continue };
+
+        let Some(instances) = device.subkeys() else { continue };
+        for instance in instances.context("Failed to get subkeys")? {
+            let instance = instance.context("Failed to enumerate key")?;
+
+            // DeviceDesc is either the name or an INF reference like "@bth.inf,%desc%;Name".
+            let friendly_name = value_bytes(&instance, "FriendlyName").ok().flatten().map(|data| decode_utf16(&data));
+            let description = value_bytes(&instance, "DeviceDesc").ok().flatten()
+                .map(|data| decode_utf16(&data).rsplit(';').next().unwrap_or_default().to_string());
+            if let Some(found) = friendly_name.into_iter().chain(description).find(|name| !name.is_empty()) {
+                bt_name_map.entry(mac.to_string()).or_insert(found);
+            }
+
+            if let Ok(Some(container)) = value_bytes(&instance, "ContainerID") {
+                containers.entry(mac.to_string()).or_insert_with(|| decode_utf16(&container).to_lowercase());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Friendly name of a device container (DEVPKEY_DeviceContainer_FriendlyName).
+fn get_container_name<B: ByteSlice>(hive: &Hive<B>, container: &str) -> Option<String> {
+    let path = format!(r"ControlSet001\Control\DeviceContainers\{}\Properties\{{656a3bb3-ecc0-43fd-8477-4ae0404a96cd}}\3000", container);
+    let root = hive.root_key_node().ok()?;
+    let key = root.subpath(&path)?.ok()?;
+    let values = key.values()?.ok()?;
+    values.filter_map(|val| val.ok()?.data().ok()?.into_vec().ok())
+        .map(|data| decode_utf16(&data))
+        .find(|name| !name.is_empty())
+}
+
+/// Device names by lower-case MAC, from the first place that has one: the BTHPORT
+/// `Devices` key, the `Enum\BTHLE` and `Enum\BTHENUM` device nodes, then the device
+/// container the device belongs to. LE devices paired through Swift Pair often
+/// have no name in `Devices`.
 fn get_bt_name_map<B: ByteSlice>(hive: &Hive<B>) -> Result<HashMap<String, String>> {
     let mut bt_name_map = HashMap::new();
+    let mut containers = HashMap::new();
     
     if let Some(keys) = hive.root_key_node()?.subpath(r"ControlSet001\Services\BTHPORT\Parameters\Devices") {
         if let Some(subs) = keys?.subkeys() {
             for key in subs.context("Failed to get subkeys")? {
                 let key = key.context("Failed to enumerate key")?;
-                if let Some(val) = key.value("Name") {
-                    if let KeyValueData::Small(data) = val.context("failed to get binary data")?.data()? {
-                        let s = std::str::from_utf8(&data[..data.iter().position(|&r| r == 0).unwrap_or(data.len())]).unwrap();
-                        bt_name_map.insert(key.name().context("failed to get name")?.to_string(), s.to_string());
+                let mac = key.name().context("failed to get name")?.to_string().to_lowercase();
+                for value in ["Name", "LEName", "FriendlyName"] {
+                    match value_bytes(&key, value) {
+                        Ok(Some(data)) => {
+                            let name = decode_name(&data, &mac);
+                            if !name.is_empty() {
+                                bt_name_map.insert(mac.clone(), name);
+                                break;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => eprintln!("{}", yellow!("Warning: cannot read the {} of {}: {:#}", value, mac, e)),
                     }
                 }
+                if let Some(container) = value_bytes(&key, "LeContainerId").ok().flatten().and_then(|data| guid_string(&data)) {
+                    containers.insert(mac, container);
+                }
+            }
+        }
+    }
+
+    for bus in ["BTHLE", "BTHENUM"] {
+        get_enum_names(hive, bus, &mut bt_name_map, &mut containers)?;
+    }
+
+    for (mac, container) in containers {
+        if let Entry::Vacant(entry) = bt_name_map.entry(mac) {
+            if let Some(name) = get_container_name(hive, &container) {
+                entry.insert(name);
             }
         }
     }
@@ -26,42 +177,268 @@ fn get_bt_name_map<B: ByteSlice>(hive: &Hive<B>) -> Result<HashMap<String, Strin
     Ok(bt_name_map)
 }
 
-fn get_bt_device_info<B: ByteSlice>(hive: &Hive<B>, bt_name_map: &HashMap<String, String>) -> Result<HashMap<String, BtDeviceInfo>> {
-    let mut bt_device_info = HashMap::new();
+/// Class, appearance and device ID of each device in `Devices`, by lower-case MAC. A class
+/// or appearance of 0 is what Windows stores when it never learned one, so it is left empty.
+fn get_bt_metadata<B: ByteSlice>(hive: &Hive<B>) -> Result<HashMap<String, BtDeviceInfo>> {
+    let mut metadata = HashMap::new();
 
-    if let Some(keys) = hive.root_key_node()?.subpath(r"ControlSet001\Services\BTHPORT\Parameters\Keys") {
+    if let Some(keys) = hive.root_key_node()?.subpath(r"ControlSet001\Services\BTHPORT\Parameters\Devices") {
+        if let Some(subs) = keys?.subkeys() {
+            for key in subs.context("Failed to get subkeys")? {
+                let key = key.context("Failed to enumerate key")?;
+                let mac = key.name().context("Failed to get name")?.to_string().to_lowercase();
+                let number = |name: &str| match value_number(&key, name) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        eprintln!("{}", yellow!("Warning: cannot read {} of {}: {:#}", name, mac, e));
+                        None
+                    }
+                };
+
+                let info = BtDeviceInfo {
+                    class: number("COD").filter(|&cod| cod != 0).map(|cod| format!("0x{:06x}", cod)).unwrap_or_default(),
+                    appearance: number("LEAppearance").filter(|&appearance| appearance != 0).map(|appearance| format!("0x{:04x}", appearance)).unwrap_or_default(),
+                    device_id_source: number("VIDType").map(|source| source.to_string()).unwrap_or_default(),
+                    vendor: number("VID").map(|vendor| vendor.to_string()).unwrap_or_default(),
+                    product: number("PID").map(|product| product.to_string()).unwrap_or_default(),
+                    version: number("Version").map(|version| version.to_string()).unwrap_or_default(),
+                    ..Default::default()
+                };
+                metadata.insert(mac, info);
+            }
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Hive bins start after the 4 KiB base block; cell offsets count from there.
+const HIVE_BINS_OFFSET: usize = 4096;
+/// Where the base block keeps the root key's cell offset.
+const ROOT_CELL_OFFSET: usize = 0x24;
+
+/// Key node fields, from the start of the cell, after its 4 byte size.
+const NK_FLAGS: usize = 6;
+const NK_LAST_WRITTEN: usize = 8;
+const NK_SUBKEY_LIST: usize = 32;
+const NK_NAME_LENGTH: usize = 76;
+const NK_NAME: usize = 80;
+/// The name is Latin-1 rather than UTF-16.
+const KEY_COMP_NAME: u16 = 0x0020;
+
+/// Seconds between the FILETIME epoch (1601-01-01) and the Unix epoch.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+pub fn filetime_to_system_time(filetime: u64) -> Option<SystemTime> {
+    let unix_100ns = filetime.checked_sub(FILETIME_UNIX_OFFSET * 10_000_000)?;
+    Some(UNIX_EPOCH + Duration::from_nanos(unix_100ns.saturating_mul(100)))
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// File position of the cell at `offset`.
+fn cell_position(offset: u32) -> Option<usize> {
+    HIVE_BINS_OFFSET.checked_add(usize::try_from(offset).ok()?)
+}
+
+/// File position of the key node at `offset`, checked to be one.
+fn key_cell(data: &[u8], offset: u32) -> Option<usize> {
+    let cell = cell_position(offset)?;
+    (data.get(cell + 4..cell + 6)? == b"nk").then_some(cell)
+}
+
+fn key_cell_name(data: &[u8], cell: usize) -> Option<String> {
+    let len = usize::from(le_u16(data, cell + NK_NAME_LENGTH)?);
+    let name = data.get(cell + NK_NAME..cell + NK_NAME + len)?;
+    if le_u16(data, cell + NK_FLAGS)? & KEY_COMP_NAME != 0 {
+        Some(name.iter().map(|&b| char::from(b)).collect())
+    } else {
+        Some(String::from_utf16_lossy(&name.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<u16>>()))
+    }
+}
+
+/// Cell offsets of the keys a subkey list points to, following `ri` index roots.
+fn subkey_offsets(data: &[u8], list: u32, offsets: &mut Vec<u32>) -> Option<()> {
+    let cell = cell_position(list)?;
+    let count = usize::from(le_u16(data, cell + 6)?);
+    let (stride, nested) = match data.get(cell + 4..cell + 6)? {
+        b"lf" | b"lh" => (8, false),
+        b"li" => (4, false),
+        b"ri" => (4, true),
+        _ => return None,
+    };
+    for i in 0..count {
+        let offset = le_u32(data, cell + 8 + i * stride)?;
+        if nested { subkey_offsets(data, offset, offsets)? } else { offsets.push(offset) }
+    }
+    Some(())
+}
+
+/// File position of the key node at `path`, a backslash separated path from the root,
+/// read straight from the cells since nt-hive does not expose where a key lives.
+pub(crate) fn key_position(data: &[u8], path: &str) -> Option<usize> {
+    let mut cell = key_cell(data, le_u32(data, ROOT_CELL_OFFSET)?)?;
+    for name in path.split('\\').filter(|name| !name.is_empty()) {
+        cell = subkey_position(data, cell, name)?;
+    }
+    Some(cell)
+}
+
+/// File position of the subkey `name` of the key node at `cell`, ignoring case like Windows.
+pub(crate) fn subkey_position(data: &[u8], cell: usize, name: &str) -> Option<usize> {
+    let mut offsets = Vec::new();
+    subkey_offsets(data, le_u32(data, cell + NK_SUBKEY_LIST)?, &mut offsets)?;
+    offsets.into_iter()
+        .filter_map(|offset| key_cell(data, offset))
+        .find(|&subkey| key_cell_name(data, subkey).is_some_and(|subkey_name| subkey_name.to_lowercase() == name.to_lowercase()))
+}
+
+/// Last-written time of the key node at `cell`, which nt-hive does not expose.
+pub(crate) fn key_last_written(data: &[u8], cell: usize) -> Option<SystemTime> {
+    let filetime = u64::from_le_bytes(data.get(cell + NK_LAST_WRITTEN..cell + NK_LAST_WRITTEN + 8)?.try_into().ok()?);
+    filetime_to_system_time(filetime)
+}
+
+/// A pairing in the hive that did not make it into the device map.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedKey {
+    pub adapter: String,
+    pub mac: String,
+    pub reason: String,
+    pub hint: String,
+}
+
+/// Fills in the LE keys of a device subkey, or returns false when it holds no LTK.
+fn read_le_key<H: Deref<Target = Hive<B>>, B: ByteSlice>(key: &KeyNode<H, B>, info: &mut BtDeviceInfo) -> Result<bool> {
+    let ltk = value_bytes(key, "LTK")?.unwrap_or_default();
+    if ltk.is_empty() {
+        return Ok(false);
+    }
+
+    info.ltk = hex(&ltk);
+    // Windows keeps the IRK little-endian, BlueZ writes it the other way round.
+    info.irk = value_bytes(key, "IRK")?.map(|irk| hex_reversed(&irk)).unwrap_or_default();
+    info.erand = format!("{}", value_number(key, "ERand")?.unwrap_or(0));
+    info.ediv = format!("{}", value_number(key, "EDIV")?.unwrap_or(0));
+    info.address_type = if value_number(key, "AddressType")?.unwrap_or(0) == 0 { "public" } else { "static" }.to_string();
+    info.enc_size = format!("{}", value_number(key, "KeyLength")?.unwrap_or(16));
+    Ok(true)
+}
+
+fn get_bt_device_info<B: ByteSlice>(
+    hive: &Hive<B>,
+    hive_data: &[u8],
+    bt_name_map: &HashMap<String, String>,
+    skipped: &mut Vec<SkippedKey>
+) -> Result<DeviceMap> {
+    let mut bt_device_info = DeviceMap::new();
+    let metadata = get_bt_metadata(hive)?;
+
+    let keys_cell = key_position(hive_data, BTHPORT_KEYS);
+    if let Some(keys) = hive.root_key_node()?.subpath(BTHPORT_KEYS) {
         if let Some(subkeys) = keys?.subkeys() {
             for dev in subkeys.context("Failed to get subkeys")? {
                 let dev = dev.context("Failed to enumerate key")?;
-                if let Some(subs) = dev.subkeys() {
-                    for key in subs.context("Failed to get subkeys")? {
-                        let key = key.context("Failed to enumerate key")?;
+                let adapter_name = dev.name().context("Failed to get name")?.to_string();
+                let adapter = fmt_mac(&adapter_name);
+                let adapter_cell = keys_cell.and_then(|cell| subkey_position(hive_data, cell, &adapter_name));
+                let adapter_written = adapter_cell.and_then(|cell| key_last_written(hive_data, cell));
 
-                        let mut ltk = String::new();
-                        let mut erand: u64 = 0;
-                        let mut ediv: u32 = 0;
+                // The adapter's own identity key, renamed from MasterIRK in newer Windows builds.
+                let mut local_irk = String::new();
+                for name in ["CentralIRK", "MasterIRK"] {
+                    match value_bytes(&dev, name) {
+                        Ok(Some(data)) => {
+                            local_irk = hex_reversed(&data);
+                            break;
+                        }
+                        Ok(None) => {}
+                        Err(e) => eprintln!("{}", yellow!("Warning: cannot read {} of adapter {}: {:#}", name, adapter, e)),
+                    }
+                }
 
-                        if let Some(val) = key.value("LTK") {
-                            if let KeyValueData::Small(data) = val.context("Failed to get binary data")?.data()? {
-                                ltk = data.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+                let mut adapter_devices: HashMap<String, BtDeviceInfo> = HashMap::new();
+                let new_device = |mac: &str| BtDeviceInfo {
+                    adapter: adapter.clone(),
+                    mac: fmt_mac(mac),
+                    address_type: "public".to_string(),
+                    local_irk: local_irk.clone(),
+                    ..Default::default()
+                };
+                let malformed = |mac: &str, e: anyhow::Error| {
+                    eprintln!("{}", yellow!("Warning: skipping {} on adapter {}: {:#}", fmt_mac(mac), adapter, e));
+                    SkippedKey {
+                        adapter: adapter.clone(),
+                        mac: fmt_mac(mac),
+                        reason: format!("malformed registry value: {:#}", e),
+                        hint: "check the key with `bt-sync inspect --hive`, or re-pair the device on Windows".to_string(),
+                    }
+                };
+
+                // BR/EDR link keys are plain values named after the device address.
+                if let Some(values) = dev.values() {
+                    for val in values.context("Failed to get values")? {
+                        let val = val.context("Failed to enumerate value")?;
+                        let mac = val.name().context("Failed to get name")?.to_string().to_lowercase();
+                        if mac.len() != 12 || !mac.chars().all(|c| c.is_ascii_hexdigit()) {
+                            continue;
+                        }
+                        match val.data().and_then(|data| data.into_vec()) {
+                            Ok(data) => {
+                                let info = adapter_devices.entry(mac.clone()).or_insert_with(|| new_device(&mac));
+                                info.link_key = hex(&data);
+                                // Link keys are values, so only the adapter key records when they changed.
+                                info.last_written = info.last_written.max(adapter_written);
                             }
+                            Err(e) => skipped.push(malformed(&mac, e.into())),
                         }
+                    }
+                }
+
+                if let Some(subs) = dev.subkeys() {
+                    for key in subs.context("Failed to get subkeys")? {
+                        let key = key.context("Failed to enumerate key")?;
+                        let mac = key.name().context("Failed to get name")?.to_string().to_lowercase();
 
-                        if let Some(val) = key.value("ERand") {erand = val?.qword_data().context(format!("Error getting ERand data"))?;}
-                        if let Some(val) = key.value("EDIV") {ediv = val?.dword_data().context(format!("Error getting EDIV data"))?;}
-
-                        if !ltk.is_empty() {
-                            if let Some(bt_name) = bt_name_map.get(&key.name().context("Failed to get name")?.to_string()) {
-                                bt_device_info.insert(bt_name.clone(), BtDeviceInfo {
-                                    mac: fmt_mac(&key.name().context("Failed to get name")?.to_string()),
-                                    ltk: ltk,
-                                    erand: format!("{}", erand),
-                                    ediv: format!("{}", ediv),
-                                });
+                        let mut info = adapter_devices.get(&mac).cloned().unwrap_or_else(|| new_device(&mac));
+                        match read_le_key(&key, &mut info) {
+                            Ok(true) => {
+                                let written = adapter_cell.and_then(|cell| subkey_position(hive_data, cell, &mac)).and_then(|cell| key_last_written(hive_data, cell));
+                                info.last_written = info.last_written.max(written);
+                                adapter_devices.insert(mac, info);
                             }
+                            Ok(false) if !adapter_devices.contains_key(&mac) => skipped.push(SkippedKey {
+                                adapter: adapter.clone(),
+                                mac: fmt_mac(&mac),
+                                reason: "LE key has no LTK".to_string(),
+                                hint: "Windows only keeps an LTK for bonded devices, remove and re-pair it on Windows".to_string(),
+                            }),
+                            Ok(false) => {}
+                            Err(e) => skipped.push(malformed(&mac, e)),
                         }
                     }
                 }
+
+                for (mac, mut info) in adapter_devices {
+                    if let Some(found) = metadata.get(&mac) {
+                        info.class = found.class.clone();
+                        info.appearance = found.appearance.clone();
+                        info.device_id_source = found.device_id_source.clone();
+                        info.vendor = found.vendor.clone();
+                        info.product = found.product.clone();
+                        info.version = found.version.clone();
+                    }
+
+                    // Keys without a name anywhere are kept under their address.
+                    info.name = bt_name_map.get(&mac).cloned().unwrap_or_else(|| info.mac.clone());
+                    bt_device_info.insert(info.key(), info);
+                }
             }
         }
     }
@@ -69,31 +446,262 @@ fn get_bt_device_info<B: ByteSlice>(hive: &Hive<B>, bt_name_map: &HashMap<String
     Ok(bt_device_info)
 }
 
-pub fn parse_reg(device: &str, mountpoint: &str) -> Result<HashMap<String, BtDeviceInfo>> {
+fn read_hive(mountpoint: &str) -> Result<Option<Vec<u8>>> {
     let path = format!("{}/Windows/System32/config/SYSTEM", mountpoint);
     if !std::path::Path::new(&path).exists() {
-        return Ok(HashMap::new());
+        return Ok(None);
     }
 
     let mut file = fs::File::open(path).context("Failed to open hive")?;
     let mut buf = Vec::<u8>::new();
     file.read_to_end(&mut buf).context("Failed to read hive")?;
+    Ok(Some(buf))
+}
 
-    let hive = Hive::new(buf.as_ref()).context("Failed to parse hive")?;
-    
+/// The device map, and the pairings that were left out of it.
+pub type HiveDevices = (DeviceMap, Vec<SkippedKey>);
+
+/// Reads the device map like `parse_reg`, without printing it.
+pub fn diagnose_reg(mountpoint: &str) -> Result<Option<HiveDevices>> {
+    let Some(buf) = read_hive(mountpoint)? else { return Ok(None) };
+    read_hive_devices(&buf).map(Some)
+}
+
+/// Reads the device map from the bytes of a `SYSTEM` hive.
+pub fn read_hive_devices(buf: &[u8]) -> Result<HiveDevices> {
+    let hive = Hive::new(buf).context("Failed to parse hive")?;
+
+    let mut skipped = Vec::new();
     let bt_name_map = get_bt_name_map(&hive)?;
-    let bt_device_info = get_bt_device_info(&hive, &bt_name_map)?;
+    let bt_device_info = get_bt_device_info(&hive, buf, &bt_name_map, &mut skipped)?;
+    Ok((bt_device_info, skipped))
+}
 
-    println!("{}", green!("=== Get Windows bluetooth info from {} ===", red!("{}", device)));
+pub fn parse_reg(device: &str, mountpoint: &str) -> Result<DeviceMap> {
+    parse_install(device, mountpoint).map(|(bt_device_info, _)| bt_device_info)
+}
 
-    println!("{} |      {} |      {}", blue!("{:<30}", "Device Name"), blue!("{:<24}", "Address"), blue!("{:<40} ", "Key"));
-    println!("{}", "-".repeat(102));
-    for (name, info) in &bt_device_info {
-        println!("{} |      {} |      {}", 
-            rgb!(0xf0, 0x00, 0x56, "{:<30}", name), 
-            rgb!(0xaa, 0x96, 0xda, "{:<24}", info.mac), 
-            rgb!(0xaa, 0x96, 0xda, "{:<40}", info.ltk));
+/// Reads and prints the devices of the Windows install on `device`, with the keys left out.
+pub fn parse_install(device: &str, mountpoint: &str) -> Result<HiveDevices> {
+    let Some((mut bt_device_info, skipped)) = diagnose_reg(mountpoint)? else { return Ok(HiveDevices::default()) };
+    for info in bt_device_info.values_mut() {
+        info.source = device.to_string();
     }
+    print_devices("Windows", device, &bt_device_info);
+    Ok((bt_device_info, skipped))
+}
 
-    Ok(bt_device_info)
-}
\ No newline at end of file
+/// Devices whose keys `patch_hive` wrote, and the ones it could not.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HivePatch {
+    pub updated: Vec<String>,
+    /// Device name and why it needs a `.reg` import instead.
+    pub unsupported: Vec<(String, String)>,
+}
+
+/// Bytes of the hive file to replace.
+type HiveWrite = (Range<usize>, Vec<u8>);
+
+/// Where a value's data sits in the hive file, `None` when it is missing or stored as big data.
+fn value_range<H: Deref<Target = Hive<B>>, B: ByteSlice>(hive_data: &[u8], key: &KeyNode<H, B>, name: &str) -> Result<Option<Range<usize>>> {
+    let Some(val) = key.value(name) else { return Ok(None) };
+    let val = val.with_context(|| format!("Failed to get {}", name))?;
+    match val.data().with_context(|| format!("Failed to read {} data", name))? {
+        KeyValueData::Small(data) => {
+            let start = data.as_ptr() as usize - hive_data.as_ptr() as usize;
+            Ok(Some(start..start + data.len()))
+        }
+        KeyValueData::Big(_) => Ok(None),
+    }
+}
+
+/// Queues `data` to replace value `name`, which must already exist with the same size.
+fn put_value<H: Deref<Target = Hive<B>>, B: ByteSlice>(
+    writes: &mut Vec<HiveWrite>,
+    hive_data: &[u8],
+    key: &KeyNode<H, B>,
+    name: &str,
+    data: Vec<u8>
+) -> Result<(), String> {
+    match value_range(hive_data, key, name).map_err(|e| format!("{:#}", e))? {
+        Some(range) if range.len() == data.len() => {
+            writes.push((range, data));
+            Ok(())
+        }
+        Some(range) => Err(format!("{} has {} bytes in the hive, {} needed", name, range.len(), data.len())),
+        None => Err(format!("the hive has no {} value to overwrite", name)),
+    }
+}
+
+/// Like `put_value` for a number, written as a DWORD or QWORD to match the existing value.
+fn put_number<H: Deref<Target = Hive<B>>, B: ByteSlice>(
+    writes: &mut Vec<HiveWrite>,
+    hive_data: &[u8],
+    key: &KeyNode<H, B>,
+    name: &str,
+    number: &str
+) -> Result<(), String> {
+    let number: u64 = number.parse().map_err(|_| format!("{} {:?} is not a number", name, number))?;
+    let size = value_range(hive_data, key, name).map_err(|e| format!("{:#}", e))?.map_or(8, |range| range.len());
+    let data = if size == 4 { (number as u32).to_le_bytes().to_vec() } else { number.to_le_bytes().to_vec() };
+    put_value(writes, hive_data, key, name, data)
+}
+
+fn device_writes<B: ByteSlice>(hive: &Hive<B>, hive_data: &[u8], info: &BtDeviceInfo) -> Result<Vec<HiveWrite>, String> {
+    let root = hive.root_key_node().map_err(|e| e.to_string())?;
+    let adapter = root.subpath(&format!(r"{}\{}", BTHPORT_KEYS, reg_name(&info.adapter)))
+        .transpose()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Windows has no pairings on adapter {}", info.adapter))?;
+
+    let mut writes = Vec::new();
+    if !info.link_key.is_empty() {
+        put_value(&mut writes, hive_data, &adapter, &reg_name(&info.mac), hex_bytes(&info.link_key))?;
+    }
+    if !info.ltk.is_empty() {
+        let key = adapter.subkey(&reg_name(&info.mac))
+            .transpose()
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Windows has no LE pairing for {}", info.mac))?;
+        put_value(&mut writes, hive_data, &key, "LTK", hex_bytes(&info.ltk))?;
+        put_number(&mut writes, hive_data, &key, "ERand", &info.erand)?;
+        put_number(&mut writes, hive_data, &key, "EDIV", &info.ediv)?;
+        if !info.enc_size.is_empty() {
+            put_number(&mut writes, hive_data, &key, "KeyLength", &info.enc_size)?;
+        }
+        if !info.irk.is_empty() {
+            let mut irk = hex_bytes(&info.irk);
+            irk.reverse();
+            put_value(&mut writes, hive_data, &key, "IRK", irk)?;
+        }
+    }
+    Ok(writes)
+}
+
+/// The base block's primary and secondary sequence numbers, which differ while Windows is
+/// in the middle of writing the hive.
+fn sequence_numbers(buf: &[u8]) -> Option<(u32, u32)> {
+    let number = |offset: usize| Some(u32::from_le_bytes(buf.get(offset..offset + 4)?.try_into().ok()?));
+    Some((number(4)?, number(8)?))
+}
+
+/// Whether a transaction log holds changes not yet written into the hive: entries from the
+/// hive's sequence number onwards, a dirty vector in the pre-Windows 8.1 format, or anything
+/// else that is not zeroed.
+fn log_is_pending(log: &[u8], sequence: u32) -> bool {
+    let Some(entries) = log.get(LOG_ENTRIES_OFFSET..) else { return false };
+    match entries.get(..4) {
+        Some(b"HvLE") => entries.get(12..16).is_none_or(|entry| u32::from_le_bytes(entry.try_into().unwrap()) >= sequence),
+        Some(b"DIRT") => sequence_numbers(log).is_none_or(|(primary, _)| primary >= sequence),
+        _ => entries.iter().any(|&b| b != 0),
+    }
+}
+
+fn ensure_complete(buf: &[u8]) -> Result<()> {
+    match sequence_numbers(buf) {
+        Some((primary, secondary)) if primary != secondary => {
+            bail!("The hive was not written completely (sequence numbers {} and {}), shut Windows down fully and try again", primary, secondary)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the keys of pairings the hive already holds, in place. Adding devices or
+/// resizing values would mean allocating cells, so those devices are reported instead.
+/// A hive Windows was still writing is refused.
+pub fn patch_hive(buf: &mut [u8], bt_device_info: &DeviceMap) -> Result<HivePatch> {
+    let mut patch = HivePatch::default();
+    let mut writes = Vec::new();
+
+    ensure_complete(buf)?;
+
+    let hive = Hive::new(&buf[..]).context("Failed to parse hive")?;
+    let mut devices: Vec<&BtDeviceInfo> = bt_device_info.values().collect();
+    devices.sort_by(|a, b| (&a.name, &a.adapter, &a.mac).cmp(&(&b.name, &b.adapter, &b.mac)));
+    for info in devices {
+        match device_writes(&hive, &buf[..], info) {
+            Ok(device) if device.iter().all(|(range, data)| buf[range.clone()] == data[..]) => {}
+            Ok(device) => {
+                writes.extend(device);
+                patch.updated.push(info.name.clone());
+            }
+            Err(reason) => patch.unsupported.push((info.name.clone(), reason)),
+        }
+    }
+
+    for (range, data) in writes {
+        buf[range].copy_from_slice(&data);
+    }
+    Ok(patch)
+}
+
+fn print_hive_patch(path: &str, patch: &HivePatch) {
+    println!("{}", green!("\n=== Update Windows hive {} ===", path));
+
+    for name in &patch.updated {
+        println!("{} |      {}", rgb!(0xf0, 0x00, 0x56, "{:<30}", name), green!("updated"));
+    }
+    for (name, reason) in &patch.unsupported {
+        println!("{} |      {}", rgb!(0xf0, 0x00, 0x56, "{:<30}", name), yellow!("{}", reason));
+    }
+    if !patch.unsupported.is_empty() {
+        eprintln!("{}", yellow!("\n{} device(s) cannot be written into the hive directly, use --sink reg:<file> and import it on Windows", patch.unsupported.len()));
+    }
+}
+
+/// A `SYSTEM` hive file, such as `Windows/System32/config/SYSTEM` on a mounted partition.
+pub struct HiveFile {
+    pub path: PathBuf,
+}
+
+impl PairingSource for HiveFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let buf = fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let (mut bt_device_info, _) = read_hive_devices(&buf)?;
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        print_devices("Windows", &self.path.display().to_string(), &bt_device_info);
+        Ok(bt_device_info)
+    }
+}
+
+impl PairingSink for HiveFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+        let mut buf = fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        ensure_complete(&buf)?;
+        let sequence = sequence_numbers(&buf).map_or(0, |(_, secondary)| secondary);
+        for log in [".LOG1", ".LOG2"] {
+            let mut log_path = self.path.as_os_str().to_owned();
+            log_path.push(log);
+            let log_path = PathBuf::from(log_path);
+            let log_data = fs::read(&log_path).unwrap_or_default();
+            if log_is_pending(&log_data, sequence) {
+                bail!("{} holds changes Windows has not written into the hive yet, shut Windows down fully (not hibernated or with Fast Startup) and try again", log_path.display());
+            }
+        }
+        let (existing, _) = read_hive_devices(&buf)?;
+        let mut hive_adapters: Vec<String> = existing.values().map(|info| info.adapter.clone()).collect();
+        hive_adapters.sort();
+        hive_adapters.dedup();
+
+        let bt_device_info = map_adapters(bt_device_info, &hive_adapters, options);
+        let patch = patch_hive(&mut buf, &bt_device_info)?;
+        if !patch.updated.is_empty() {
+            let mut backup = self.path.as_os_str().to_owned();
+            backup.push(".bak");
+            fs::copy(&self.path, backup)?;
+            write_atomic(&self.path, &buf)?;
+        }
+        print_hive_patch(&self.path.display().to_string(), &patch);
+        Ok(!patch.updated.is_empty())
+    }
+}
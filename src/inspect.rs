@@ -0,0 +1,213 @@
+use std::{fmt::Write, fs, ops::Deref, path::PathBuf, time::SystemTime};
+use anyhow::{Context, Result};
+use nt_hive::{Hive, KeyNode, KeyValueDataType};
+use serde::Serialize;
+use zerocopy::ByteSlice;
+
+use crate::{hive::{filetime_to_system_time, key_last_written, key_position, subkey_position}, utils::format_timestamp};
+
+pub const BTHPORT_PARAMETERS: &str = r"ControlSet001\Services\BTHPORT\Parameters";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InspectFormat {
+    #[default]
+    Tree,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InspectOptions {
+    pub hive: PathBuf,
+    pub format: InspectFormat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegKey {
+    pub name: String,
+    pub last_written: Option<SystemTime>,
+    pub values: Vec<RegValue>,
+    pub subkeys: Vec<RegKey>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegValue {
+    pub name: String,
+    pub data_type: String,
+    pub data: Vec<u8>,
+    /// Readable form of the fields bt-sync knows about.
+    pub decoded: Option<String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn le_u64(data: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(..8)?.try_into().ok()?))
+}
+
+fn le_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(..4)?.try_into().ok()?))
+}
+
+pub fn decode_value(name: &str, data: &[u8]) -> Option<String> {
+    match name {
+        "Name" => {
+            let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+            Some(String::from_utf8_lossy(&data[..end]).into_owned())
+        }
+        "LTK" => Some(hex(data)),
+        // Stored little-endian; shown the way BlueZ writes them.
+        "IRK" | "CentralIRK" | "MasterIRK" => Some(data.iter().rev().map(|b| format!("{:02X}", b)).collect()),
+        "ERand" => le_u64(data).map(|v| v.to_string()),
+        "EDIV" => le_u32(data).map(|v| v.to_string()),
+        "COD" => le_u32(data).map(|v| format!("0x{:06X}", v)),
+        "LastSeen" | "LastConnected" => le_u64(data).and_then(filetime_to_system_time).map(format_timestamp),
+        _ => None,
+    }
+}
+
+fn data_type_name(data_type: Option<KeyValueDataType>) -> &'static str {
+    match data_type {
+        Some(KeyValueDataType::RegNone) => "REG_NONE",
+        Some(KeyValueDataType::RegSZ) => "REG_SZ",
+        Some(KeyValueDataType::RegExpandSZ) => "REG_EXPAND_SZ",
+        Some(KeyValueDataType::RegBinary) => "REG_BINARY",
+        Some(KeyValueDataType::RegDWord) => "REG_DWORD",
+        Some(KeyValueDataType::RegDWordBigEndian) => "REG_DWORD_BIG_ENDIAN",
+        Some(KeyValueDataType::RegLink) => "REG_LINK",
+        Some(KeyValueDataType::RegMultiSZ) => "REG_MULTI_SZ",
+        Some(KeyValueDataType::RegQWord) => "REG_QWORD",
+        Some(_) => "REG_RESOURCE",
+        None => "unknown",
+    }
+}
+
+/// `cell` is where the key node sits in `hive_data`, for its last-written time.
+fn read_key<H: Deref<Target = Hive<B>>, B: ByteSlice>(hive_data: &[u8], key: &KeyNode<H, B>, cell: Option<usize>) -> Result<RegKey> {
+    let mut reg_key = RegKey {
+        name: key.name().context("Failed to get name")?.to_string_lossy(),
+        last_written: cell.and_then(|cell| key_last_written(hive_data, cell)),
+        values: Vec::new(),
+        subkeys: Vec::new(),
+    };
+
+    if let Some(values) = key.values() {
+        for val in values.context("Failed to get values")? {
+            let val = val.context("Failed to enumerate value")?;
+            let name = val.name().context("Failed to get name")?.to_string_lossy();
+            // Show a broken value instead of giving up on the whole dump.
+            let (data, decoded) = match val.data().and_then(|data| data.into_vec()) {
+                Ok(data) => { let decoded = decode_value(&name, &data); (data, decoded) }
+                Err(e) => (Vec::new(), Some(format!("unreadable: {}", e))),
+            };
+            reg_key.values.push(RegValue {
+                decoded,
+                data_type: data_type_name(val.data_type().ok()).to_string(),
+                name,
+                data,
+            });
+        }
+    }
+
+    if let Some(subkeys) = key.subkeys() {
+        for subkey in subkeys.context("Failed to get subkeys")? {
+            let subkey = subkey.context("Failed to enumerate key")?;
+            let name = subkey.name().context("Failed to get name")?.to_string_lossy();
+            let subkey_cell = cell.and_then(|cell| subkey_position(hive_data, cell, &name));
+            reg_key.subkeys.push(read_key(hive_data, &subkey, subkey_cell)?);
+        }
+    }
+
+    Ok(reg_key)
+}
+
+/// Reads the whole `BTHPORT\Parameters` subtree of a SYSTEM hive.
+pub fn inspect_hive(buf: &[u8]) -> Result<RegKey> {
+    let hive = Hive::new(buf).context("Failed to parse hive")?;
+    let root = hive.root_key_node()?;
+    let parameters = root
+        .subpath(BTHPORT_PARAMETERS)
+        .with_context(|| format!("No {} key in hive", BTHPORT_PARAMETERS))??;
+    read_key(buf, &parameters, key_position(buf, BTHPORT_PARAMETERS))
+}
+
+fn write_tree(out: &mut String, key: &RegKey, prefix: &str) {
+    let entries = key.values.len() + key.subkeys.len();
+    let branch = |i: usize| if i + 1 == entries { ("└── ", "    ") } else { ("├── ", "│   ") };
+
+    for (i, value) in key.values.iter().enumerate() {
+        let shown = match &value.decoded {
+            Some(decoded) => decoded.clone(),
+            None => format!("hex:{}", hex(&value.data)),
+        };
+        let _ = writeln!(out, "{}{}{} = {}", prefix, branch(i).0, value.name, shown);
+    }
+
+    for (i, subkey) in key.subkeys.iter().enumerate() {
+        let (head, tail) = branch(key.values.len() + i);
+        let _ = writeln!(out, "{}{}{}{}", prefix, head, subkey.name, written_suffix(subkey));
+        write_tree(out, subkey, &format!("{}{}", prefix, tail));
+    }
+}
+
+fn written_suffix(key: &RegKey) -> String {
+    key.last_written.map(|time| format!(" [{}]", format_timestamp(time))).unwrap_or_default()
+}
+
+pub fn format_tree(key: &RegKey) -> String {
+    let mut out = format!("{}{}\n", key.name, written_suffix(key));
+    write_tree(&mut out, key, "");
+    out
+}
+
+#[derive(Serialize)]
+struct JsonKey<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_written: Option<String>,
+    values: Vec<JsonValue<'a>>,
+    subkeys: Vec<JsonKey<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonValue<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    data_type: &'a str,
+    hex: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+}
+
+fn json_key(key: &RegKey) -> JsonKey<'_> {
+    JsonKey {
+        name: &key.name,
+        last_written: key.last_written.map(format_timestamp),
+        values: key.values.iter().map(|value| JsonValue {
+            name: &value.name,
+            data_type: &value.data_type,
+            hex: hex(&value.data),
+            value: value.decoded.as_deref(),
+        }).collect(),
+        subkeys: key.subkeys.iter().map(json_key).collect(),
+    }
+}
+
+pub fn format_json(key: &RegKey) -> Result<String> {
+    let mut json = serde_json::to_string_pretty(&json_key(key))?;
+    json.push('\n');
+    Ok(json)
+}
+
+pub fn process_inspect(options: &InspectOptions) -> Result<()> {
+    let buf = fs::read(&options.hive).with_context(|| format!("Failed to read {}", options.hive.display()))?;
+    let parameters = inspect_hive(&buf)?;
+
+    match options.format {
+        InspectFormat::Tree => print!("{}", format_tree(&parameters)),
+        InspectFormat::Json => print!("{}", format_json(&parameters)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+/// A GLib-style keyfile, as used by BlueZ for `info`, `cache` and `settings`.
+///
+/// Parsing and serializing an untouched file gives back the exact input,
+/// including comments, blank lines, key order, `\r\n` line endings and the
+/// final newline. Edited keys are written as `Key=Value`, ending like the
+/// first line of the file. A group that appears twice is read as one, as GLib
+/// does: the last value of a key wins, and `set` and `remove` change every copy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyFile {
+    /// Lines before the first group header.
+    preamble: Vec<Line>,
+    groups: Vec<Group>,
+    trailing_newline: bool,
+    crlf: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    name: String,
+    header: String,
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Entry { key: String, value: String, raw: String },
+    Other(String),
+}
+
+impl Default for KeyFile {
+    fn default() -> Self {
+        KeyFile { preamble: Vec::new(), groups: Vec::new(), trailing_newline: true, crlf: false }
+    }
+}
+
+impl Line {
+    fn parse(line: &str) -> Line {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Line::Other(line.to_string());
+        }
+
+        match line.strip_suffix('\r').unwrap_or(line).split_once('=') {
+            Some((key, value)) => Line::Entry {
+                key: key.trim().to_string(),
+                value: value.trim_start().to_string(),
+                raw: line.to_string(),
+            },
+            None => Line::Other(line.to_string()),
+        }
+    }
+
+    fn is_entry(&self) -> bool {
+        matches!(self, Line::Entry { .. })
+    }
+
+    fn raw(&self) -> &str {
+        match self {
+            Line::Entry { raw, .. } => raw,
+            Line::Other(raw) => raw,
+        }
+    }
+}
+
+impl Group {
+    fn entry(&self, key: &str) -> Option<&str> {
+        self.lines.iter().rev().find_map(|line| match line {
+            Line::Entry { key: k, value, .. } if k == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl KeyFile {
+    pub fn parse(c: &str) -> KeyFile {
+        let mut keyfile = KeyFile {
+            preamble: Vec::new(),
+            groups: Vec::new(),
+            trailing_newline: c.is_empty() || c.ends_with('\n'),
+            crlf: c.split('\n').next().is_some_and(|line| line.ends_with('\r')),
+        };
+
+        // Lines keep their '\r' so that `\r\n` files are written back unchanged.
+        let content = c.strip_suffix('\n').unwrap_or(c);
+        for line in content.split('\n').filter(|_| !c.is_empty()) {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                keyfile.groups.push(Group {
+                    name: trimmed[1..trimmed.len() - 1].to_string(),
+                    header: line.to_string(),
+                    lines: Vec::new(),
+                });
+                continue;
+            }
+
+            match keyfile.groups.last_mut() {
+                Some(group) => group.lines.push(Line::parse(line)),
+                None => keyfile.preamble.push(Line::parse(line)),
+            }
+        }
+
+        keyfile
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.iter().enumerate()
+            .filter(|(i, group)| !self.groups[..*i].iter().any(|earlier| earlier.name == group.name))
+            .map(|(_, group)| group.name.as_str())
+    }
+
+    pub fn keys(&self, group: &str) -> Vec<&str> {
+        let mut keys = Vec::new();
+        for line in self.copies(group).flat_map(|group| &group.lines) {
+            if let Line::Entry { key, .. } = line {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key.as_str());
+                }
+            }
+        }
+        keys
+    }
+
+    pub fn has_group(&self, group: &str) -> bool {
+        self.copies(group).next().is_some()
+    }
+
+    pub fn get(&self, group: &str, key: &str) -> Option<&str> {
+        self.copies(group).filter_map(|group| group.entry(key)).last()
+    }
+
+    /// Adds an empty group at the end of the file unless it already exists.
+    pub fn add_group(&mut self, group: &str) {
+        if self.has_group(group) {
+            return;
+        }
+
+        let ending = self.carriage_return();
+        let previous = match self.groups.last_mut() {
+            Some(last) => &mut last.lines,
+            None => &mut self.preamble,
+        };
+        if previous.last().is_some_and(|line| !line.raw().trim().is_empty()) {
+            previous.push(Line::Other(ending.to_string()));
+        }
+
+        self.groups.push(Group {
+            name: group.to_string(),
+            header: format!("[{}]{}", group, ending),
+            lines: Vec::new(),
+        });
+    }
+
+    /// Copies every group of `other` that this file does not have yet.
+    pub fn add_missing_groups(&mut self, other: &KeyFile) {
+        let mut added = Vec::new();
+        for group in &other.groups {
+            if added.contains(&group.name) {
+                let copy = self.groups.iter_mut().rfind(|g| g.name == group.name).unwrap();
+                copy.lines.extend(group.lines.iter().cloned());
+            } else if !self.has_group(&group.name) {
+                self.add_group(&group.name);
+                self.groups.last_mut().unwrap().lines = group.lines.clone();
+                added.push(group.name.clone());
+            }
+        }
+    }
+
+    /// Removes every copy of the group. When the group ends the file or has blank lines
+    /// of its own, the blank lines before it go too, so separators do not pile up.
+    pub fn remove_group(&mut self, group: &str) -> bool {
+        let blank = |line: &Line| line.raw().trim().is_empty();
+        let mut removed = false;
+        while let Some(index) = self.groups.iter().position(|g| g.name == group) {
+            let lines = self.groups.remove(index).lines;
+            let separator = lines.len() - lines.iter().rev().take_while(|line| blank(line)).count();
+            let last = index == self.groups.len();
+            let previous = match index {
+                0 => &mut self.preamble,
+                _ => &mut self.groups[index - 1].lines,
+            };
+            if last || separator < lines.len() {
+                while previous.last().is_some_and(blank) {
+                    previous.pop();
+                }
+                if !previous.is_empty() {
+                    previous.extend(lines.into_iter().skip(separator));
+                }
+            }
+            removed = true;
+        }
+        removed
+    }
+
+    /// Replaces the value of an existing key, or adds the key after the last
+    /// entry of the group, creating the group if needed.
+    pub fn set(&mut self, group: &str, key: &str, value: &str) {
+        self.add_group(group);
+        let raw = format!("{}={}{}", key, value, self.carriage_return());
+
+        let mut found = false;
+        for line in self.groups.iter_mut().filter(|g| g.name == group).flat_map(|g| g.lines.iter_mut()) {
+            if let Line::Entry { key: k, value: v, raw: r } = line {
+                if k == key {
+                    if v != value {
+                        *v = value.to_string();
+                        *r = raw.clone();
+                    }
+                    found = true;
+                }
+            }
+        }
+        if found {
+            return;
+        }
+
+        let group = self.groups.iter_mut().rfind(|g| g.name == group).unwrap();
+        let position = group.lines.iter().rposition(Line::is_entry).map_or(0, |i| i + 1);
+        group.lines.insert(position, Line::Entry { key: key.to_string(), value: value.to_string(), raw });
+    }
+
+    pub fn remove(&mut self, group: &str, key: &str) -> bool {
+        let mut removed = false;
+        for group in self.groups.iter_mut().filter(|g| g.name == group) {
+            let before = group.lines.len();
+            group.lines.retain(|line| !matches!(line, Line::Entry { key: k, .. } if k == key));
+            removed |= before != group.lines.len();
+        }
+        removed
+    }
+
+    fn carriage_return(&self) -> &'static str {
+        if self.crlf { "\r" } else { "" }
+    }
+
+    fn copies<'a: 'b, 'b>(&'a self, group: &'b str) -> impl Iterator<Item = &'a Group> + 'b {
+        self.groups.iter().filter(move |g| g.name == group)
+    }
+}
+
+impl fmt::Display for KeyFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines: Vec<&str> = self.preamble.iter().map(Line::raw).collect();
+        for group in &self.groups {
+            lines.push(&group.header);
+            lines.extend(group.lines.iter().map(Line::raw));
+        }
+
+        write!(f, "{}", lines.join("\n"))?;
+        if self.trailing_newline && !lines.is_empty() {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
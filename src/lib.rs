@@ -1,4 +1,15 @@
+pub mod android;
 pub mod bluetooth;
+pub mod bluez;
+pub mod btsnoop;
+pub mod bundle;
+pub mod cli;
+pub mod doctor;
+pub mod inspect;
 pub mod partitions;
 pub mod hive;
+pub mod keyfile;
+pub mod macos;
+pub mod regfile;
+pub mod store;
 pub mod utils;
\ No newline at end of file
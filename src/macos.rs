@@ -0,0 +1,135 @@
+use std::{collections::HashMap, fs, io::Cursor, path::PathBuf};
+use anyhow::{Context, Result};
+use plist::{Dictionary, Value};
+
+use crate::{
+    bluetooth::{device_map, normalize_mac, print_devices, BtDeviceInfo, DeviceMap},
+    store::PairingSource,
+};
+
+/// Where macOS keeps its pairings, relative to the root of the system volume.
+pub const BLUETOOTHD_PLIST: &str = "private/var/root/Library/Preferences/com.apple.bluetoothd.plist";
+
+/// macOS writes addresses as `aa-bb-cc-dd-ee-ff`.
+fn plist_mac(address: &str) -> String {
+    normalize_mac(&address.replace('-', ":"))
+}
+
+/// macOS stores link keys, LTKs and IRKs in the reverse byte order of BlueZ and Windows.
+fn key_hex(data: &[u8]) -> String {
+    data.iter().rev().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Numbers appear both as integers and as little-endian data, depending on the macOS version.
+fn number(value: &Value) -> Option<u64> {
+    match value {
+        Value::Integer(n) => n.as_unsigned(),
+        Value::Data(data) if data.len() <= 8 => Some(data.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b))),
+        _ => None,
+    }
+}
+
+/// Each adapter's dictionary of devices under `section`.
+fn adapters<'a>(root: &'a Dictionary, section: &str) -> Vec<(String, &'a Dictionary)> {
+    root.get(section)
+        .and_then(Value::as_dictionary)
+        .map(|adapters| adapters.iter()
+            .filter_map(|(adapter, devices)| Some((plist_mac(adapter), devices.as_dictionary()?)))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn read_smp_keys(keys: &Dictionary, info: &mut BtDeviceInfo) {
+    let data = |name: &str| keys.get(name).and_then(Value::as_data);
+    let Some(ltk) = data("LTK") else { return };
+
+    info.ltk = key_hex(ltk);
+    info.irk = data("IRK").map(key_hex).unwrap_or_default();
+    info.ediv = keys.get("EDIV").and_then(number).unwrap_or(0).to_string();
+    info.erand = keys.get("RAND").or_else(|| keys.get("Rand")).and_then(number).unwrap_or(0).to_string();
+    info.enc_size = keys.get("KeySize").and_then(number).unwrap_or(16).to_string();
+    info.address_type = if keys.get("AddressType").and_then(number).unwrap_or(0) == 0 { "public" } else { "static" }.to_string();
+}
+
+fn device_entry<'a>(devices: &'a mut HashMap<(String, String), BtDeviceInfo>, adapter: &str, mac: &str) -> &'a mut BtDeviceInfo {
+    devices.entry((adapter.to_string(), mac.to_string())).or_insert_with(|| BtDeviceInfo {
+        adapter: adapter.to_string(),
+        mac: mac.to_string(),
+        address_type: "public".to_string(),
+        ..Default::default()
+    })
+}
+
+/// Reads `com.apple.bluetoothd.plist`, binary or XML, into the device map `hive.rs` produces.
+/// macOS keeps no device names there, so devices are named after their address.
+///
+/// Apple does not document the file; this follows what `defaults read` shows for it:
+///
+/// ```text
+/// LinkKeys            = { "<adapter>" = { "<device>" = <16 bytes>; }; };
+/// SMPDistributionKeys = { "<adapter>" = { "<device>" = {
+///     LTK = <16 bytes>; IRK = <16 bytes>; EDIV = ...; RAND = ...;
+///     KeySize = ...; AddressType = ...; Address = ...; ...
+/// }; }; };
+/// ```
+///
+/// Addresses are written `aa-bb-cc-dd-ee-ff`. The random number appears as both `RAND` and
+/// `Rand`, and numbers as integers or little-endian data, so every form is accepted. A
+/// missing `KeySize` is taken as the full 16 bytes. The device's dictionary key is used as
+/// its address, as with `LinkKeys`, which have nothing else; the `Address` field is not read.
+pub fn parse_bluetoothd_plist(bytes: &[u8]) -> Result<DeviceMap> {
+    let value = Value::from_reader(Cursor::new(bytes)).context("Failed to parse plist")?;
+    let root = value.as_dictionary().context("bluetoothd plist is not a dictionary")?;
+
+    let mut devices: HashMap<(String, String), BtDeviceInfo> = HashMap::new();
+
+    for (adapter, keys) in adapters(root, "LinkKeys") {
+        for (mac, key) in keys {
+            if let Some(key) = key.as_data() {
+                device_entry(&mut devices, &adapter, &plist_mac(mac)).link_key = key_hex(key);
+            }
+        }
+    }
+
+    for (adapter, keys) in adapters(root, "SMPDistributionKeys") {
+        for (mac, smp) in keys {
+            if let Some(smp) = smp.as_dictionary() {
+                read_smp_keys(smp, device_entry(&mut devices, &adapter, &plist_mac(mac)));
+            }
+        }
+    }
+
+    Ok(device_map(devices.into_values()
+        .filter(|info| !info.ltk.is_empty() || !info.link_key.is_empty())
+        .map(|info| BtDeviceInfo { name: info.mac.clone(), ..info })))
+}
+
+/// A copy of `com.apple.bluetoothd.plist`, or the root of a mounted macOS volume.
+pub struct PlistFile {
+    pub path: PathBuf,
+}
+
+impl PlistFile {
+    fn plist_path(&self) -> PathBuf {
+        if self.path.is_dir() { self.path.join(BLUETOOTHD_PLIST) } else { self.path.clone() }
+    }
+}
+
+impl PairingSource for PlistFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let path = self.plist_path();
+        let bytes = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut bt_device_info = parse_bluetoothd_plist(&bytes)?;
+
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        print_devices("macOS", &self.path.display().to_string(), &bt_device_info);
+
+        Ok(bt_device_info)
+    }
+}
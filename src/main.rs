@@ -1,9 +1,35 @@
 use bt_sync::*;
 use bluetooth::*;
+use doctor::process_doctor;
+use inspect::process_inspect;
+use cli::*;
 use utils::*;
-use std::process;
+use std::{env, process};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if command == Command::Help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // Reads a file named on the command line and may print JSON, so no sudo and no banner.
+    if let Command::Inspect(options) = &command {
+        if let Err(e) = process_inspect(options) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     if !is_root() {
         restart_with_sudo();
         return;
@@ -11,7 +37,18 @@ fn main() {
 
     print_colored_ascii();
 
-    if let Err(e) = process_bluetooth_devices("/var/lib/bluetooth/") {
+    let result = match command {
+        Command::Sync(options) => process_bluetooth_devices("/var/lib/bluetooth/", &options),
+        Command::Status(options) => match process_status("/var/lib/bluetooth/", &options) {
+            Ok(false) => process::exit(3),
+            result => result.map(|_| ()),
+        },
+        Command::Doctor(options) => process_doctor("/var/lib/bluetooth/", &options),
+        Command::MigrateAdapter(options) => process_adapter_migration("/var/lib/bluetooth/", &options),
+        Command::Inspect(_) | Command::Help => Ok(()),
+    };
+
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         process::exit(1);
     }
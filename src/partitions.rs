@@ -1,32 +1,212 @@
-use std::{collections::HashMap, fs, process::Command, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashMap, fs, path::Path, process::Command, time::{SystemTime, UNIX_EPOCH}};
 use anyhow::Result;
 use rand::Rng;
 use regex::Regex;
+use term_ansi::*;
 
-use crate::{bluetooth::BtDeviceInfo, hive::parse_reg};
+use crate::{bluetooth::{BtDeviceInfo, DeviceMap}, hive::{parse_install, parse_reg, HiveDevices}, store::PairingSource, utils::format_timestamp};
 
-pub fn find_and_mount_ntfs_partitions() -> Result<HashMap<String, BtDeviceInfo>> {
-    let mut bt_device_info: HashMap<String, BtDeviceInfo> = HashMap::new();
+/// Which Windows install wins when several hold a different pairing for a device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum SourcePolicy {
+    /// The first install in `lsblk` order.
+    #[default]
+    First,
+    /// The install whose registry key was written last.
+    Newest,
+    /// This partition, e.g. `/dev/nvme0n1p3`.
+    Partition(String),
+}
+
+/// A device the Windows installs disagree on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceConflict {
+    pub name: String,
+    /// Every install's pairing, in partition order; `chosen` indexes the one applied.
+    pub candidates: Vec<BtDeviceInfo>,
+    pub chosen: usize,
+}
+
+pub fn find_and_mount_ntfs_partitions() -> Result<DeviceMap> {
+    let bt_device_info = find_in_ntfs_partitions(|device, mountpoint| {
+        let info = parse_reg(device, mountpoint)?;
+        Ok((!info.is_empty()).then_some(info))
+    })?;
+
+    Ok(bt_device_info.unwrap_or_default())
+}
+
+/// Reads every Windows install and merges their devices, choosing by `policy`
+/// where they disagree. Keys are reported as skipped unless another install had them.
+pub fn collect_ntfs_partitions(policy: &SourcePolicy) -> Result<HiveDevices> {
+    let mut installs = Vec::new();
+    let mut skipped = Vec::new();
+    find_in_ntfs_partitions(|device, mountpoint| {
+        let (info, install_skipped) = parse_install(device, mountpoint)?;
+        if !info.is_empty() {
+            installs.push(info);
+        }
+        for key in install_skipped {
+            if !skipped.contains(&key) {
+                skipped.push(key);
+            }
+        }
+        Ok(None::<()>)
+    })?;
+
+    if let SourcePolicy::Partition(device) = policy {
+        if !installs.iter().any(|install| install.values().any(|info| &info.source == device)) {
+            eprintln!("{}", yellow!("Warning: no Windows pairings found on {}, using the first install instead", device));
+        }
+    }
+
+    let (bt_device_info, conflicts) = merge_installs(installs, policy);
+    print_conflicts(&conflicts);
+    skipped.retain(|key| !bt_device_info.contains_key(&(key.adapter.clone(), key.mac.clone())));
+    Ok((bt_device_info, skipped))
+}
+
+/// The Windows installs on local NTFS partitions.
+pub struct WindowsInstalls {
+    /// Read every install instead of the first, resolving conflicts this way.
+    pub aggregate: Option<SourcePolicy>,
+}
+
+impl PairingSource for WindowsInstalls {
+    fn describe(&self) -> String {
+        "any Windows installation".to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        read_windows_devices(self.aggregate.as_ref())
+    }
+}
+
+/// Devices from the first Windows install, or from all of them when `aggregate` is set.
+pub fn read_windows_devices(aggregate: Option<&SourcePolicy>) -> Result<DeviceMap> {
+    match aggregate {
+        Some(policy) => collect_ntfs_partitions(policy).map(|(bt_device_info, _)| bt_device_info),
+        None => find_and_mount_ntfs_partitions(),
+    }
+}
+
+/// Merges the device maps of several installs, given in partition order. Pairings are
+/// the same device when they have the same adapter and address.
+pub fn merge_installs(installs: Vec<DeviceMap>, policy: &SourcePolicy) -> (DeviceMap, Vec<SourceConflict>) {
+    let mut candidates: HashMap<(String, String), Vec<BtDeviceInfo>> = HashMap::new();
+    for install in installs {
+        for (key, info) in install {
+            candidates.entry(key).or_default().push(info);
+        }
+    }
+
+    let mut bt_device_info = DeviceMap::new();
+    let mut conflicts = Vec::new();
+
+    for (key, candidates) in candidates {
+        let chosen = match policy {
+            SourcePolicy::First => None,
+            // On equal timestamps the earlier partition stays, like with `first`.
+            SourcePolicy::Newest => candidates.iter().enumerate()
+                .rev()
+                .max_by_key(|(_, info)| info.last_written)
+                .map(|(i, _)| i),
+            SourcePolicy::Partition(device) => candidates.iter().position(|info| &info.source == device),
+        }.unwrap_or(0);
+
+        let first = &candidates[0];
+        if candidates.iter().any(|info| info.display_key() != first.display_key()) {
+            conflicts.push(SourceConflict { name: first.name.clone(), candidates: candidates.clone(), chosen });
+        }
+        bt_device_info.insert(key, candidates[chosen].clone());
+    }
+
+    conflicts.sort_by(|a, b| (&a.name, &a.candidates[0].adapter, &a.candidates[0].mac).cmp(&(&b.name, &b.candidates[0].adapter, &b.candidates[0].mac)));
+    (bt_device_info, conflicts)
+}
+
+fn print_conflicts(conflicts: &[SourceConflict]) {
+    if conflicts.is_empty() {
+        return;
+    }
+
+    println!("{}", green!("\n=== Devices with different pairings across Windows installs ==="));
+
+    println!("{} |      {} |      {}",
+        blue!("{:<30}", "Device Name"),
+        blue!("{:<24}", "Source"),
+        blue!("{:<40}", "Key"));
+    println!("{}", "-".repeat(102));
+
+    for conflict in conflicts {
+        for (i, info) in conflict.candidates.iter().enumerate() {
+            let name = if i == 0 { conflict.name.as_str() } else { " " };
+            let marker = if i == conflict.chosen { " USE" } else { "    " };
+            let written = info.last_written.map_or("unknown".to_string(), format_timestamp);
+            println!("{} | {} {} |      {} {} ({})",
+                rgb!(0xf0, 0x00, 0x56, "{:<30}", name),
+                marker,
+                rgb!(0xaa, 0x96, 0xda, "{:<24}", info.source),
+                rgb!(0xaa, 0x96, 0xda, "{}", info.display_key()),
+                info.mac,
+                written);
+        }
+    }
+}
+
+/// Runs `parse` on each NTFS partition, mounting it first if needed, until it finds something.
+pub fn find_in_ntfs_partitions<T>(parse: impl FnMut(&str, &str) -> Result<Option<T>>) -> Result<Option<T>> {
+    find_in_partitions(&["ntfs"], None, None, parse)
+}
+
+/// Runs `parse` on each partition of one of `fstypes`, or only on `device` when given,
+/// until it finds something, leaving out the partition that holds `skip`. Linux file systems
+/// are mounted read-only, without journal replay. btrfs is always mounted again at its top
+/// level, even when it is already mounted, since a mounted btrfs only shows one subvolume.
+pub fn find_in_partitions<T>(
+    fstypes: &[&str],
+    device: Option<&str>,
+    skip: Option<&Path>,
+    mut parse: impl FnMut(&str, &str) -> Result<Option<T>>
+) -> Result<Option<T>> {
     let partitions = get_partitions_info()?;
-    
+    let skipped = skip.and_then(mount_source);
+
     for partition in partitions {
-        if partition.fstype == "ntfs" {
-            if partition.mountpoint.is_empty() {
-                if let Some(info) = mount_and_parse_partition(&partition.device)? {
-                    bt_device_info = info;
-                    break;
-                }
+        if skipped.as_deref().is_some_and(|skipped| same_device(skipped, &partition.device)) {
+            continue;
+        }
+        if fstypes.contains(&partition.fstype.as_str()) && device.is_none_or(|device| device == partition.device) {
+            // A mounted btrfs only shows one subvolume, the top level has all of them.
+            let found = if partition.mountpoint.is_empty() || partition.fstype == "btrfs" {
+                mount_and_parse_partition(&partition.device, &partition.fstype, &mut parse)?
             } else {
-                let info = parse_reg(&partition.device, &partition.mountpoint)?;
-                if !info.is_empty() {
-                    bt_device_info = info;
-                    break;
-                }
+                parse(&partition.device, &partition.mountpoint)?
+            };
+            if found.is_some() {
+                return Ok(found);
             }
         }
     }
 
-    Ok(bt_device_info)
+    Ok(None)
+}
+
+/// The device `path` is mounted from, without the `[/subvolume]` findmnt adds for btrfs.
+fn mount_source(path: &Path) -> Option<String> {
+    let output = Command::new("findmnt").args(["-n", "-o", "SOURCE", "--target"]).arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let source = String::from_utf8_lossy(&output.stdout);
+    let device = source.trim().split('[').next()?;
+    (!device.is_empty()).then(|| device.to_string())
+}
+
+/// lsblk and findmnt can name a device mapper device differently, so links are resolved.
+fn same_device(a: &str, b: &str) -> bool {
+    let resolve = |device: &str| fs::canonicalize(device).map_or(device.to_string(), |path| path.display().to_string());
+    a == b || resolve(a) == resolve(b)
 }
 
 #[derive(Debug)]
@@ -58,13 +238,13 @@ fn get_partitions_info() -> Result<Vec<PartitionInfo>> {
     Ok(partitions)
 }
 
-fn mount_and_parse_partition(device: &str) -> Result<Option<HashMap<String, BtDeviceInfo>>> {
+fn mount_and_parse_partition<T>(device: &str, fstype: &str, parse: &mut impl FnMut(&str, &str) -> Result<Option<T>>) -> Result<Option<T>> {
     let mount_point = create_temp_mount_point()?;
-    match mount_partition(device, &mount_point) {
+    match mount_partition(device, fstype, &mount_point) {
         Ok(_) => {
-            let info = parse_reg(device, &mount_point)?;
+            let found = parse(device, &mount_point);
             umount_and_cleanup(&mount_point)?;
-            Ok(Some(info))
+            found
         },
         Err(e) => {
             umount_and_cleanup(&mount_point)?;
@@ -85,8 +265,15 @@ fn create_temp_mount_point() -> Result<String> {
     Ok(mount_point)
 }
 
-fn mount_partition(device: &str, mount_point: &str) -> Result<()> {
-    let status = Command::new("mount").args(["-t", "ntfs3", device, mount_point]).status()?;
+fn mount_partition(device: &str, fstype: &str, mount_point: &str) -> Result<()> {
+    let args = match fstype {
+        "ntfs" => vec!["-t", "ntfs3"],
+        // Replaying a journal or log tree writes to the disk even on a read-only mount.
+        "ext4" => vec!["-t", "ext4", "-o", "ro,noload"],
+        "btrfs" => vec!["-t", "btrfs", "-o", "ro,rescue=nologreplay,subvolid=5"],
+        other => vec!["-t", other, "-o", "ro"],
+    };
+    let status = Command::new("mount").args(args).args([device, mount_point]).status()?;
     if status.success() {
         Ok(())
     } else {
@@ -0,0 +1,305 @@
+use std::{collections::HashMap, fs, path::PathBuf};
+use anyhow::{Context, Result};
+use term_ansi::*;
+
+use crate::{
+    bluetooth::{fmt_mac, BtDeviceInfo, DeviceMap, SyncDecision, SyncOptions},
+    hive::decode_name,
+    store::{map_adapters, PairingSink, PairingSource},
+    utils::write_atomic,
+};
+
+pub const BTHPORT_KEYS: &str = r"HKEY_LOCAL_MACHINE\SYSTEM\CurrentControlSet\Services\BTHPORT\Parameters\Keys";
+
+pub(crate) fn reg_name(mac: &str) -> String {
+    mac.replace(':', "").to_lowercase()
+}
+
+pub(crate) fn hex_bytes(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+fn reg_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(",")
+}
+
+pub fn reg_device_entry(adapter: &str, info: &BtDeviceInfo) -> String {
+    let mut entry = String::new();
+
+    // BR/EDR link keys live directly under the adapter key, LE keys in a subkey per device.
+    if !info.link_key.is_empty() {
+        entry.push_str(&format!("[{}\\{}]\n", BTHPORT_KEYS, reg_name(adapter)));
+        entry.push_str(&format!("\"{}\"=hex:{}\n", reg_name(&info.mac), reg_hex(&hex_bytes(&info.link_key))));
+        if info.ltk.is_empty() {
+            return entry;
+        }
+        entry.push('\n');
+    }
+
+    entry.push_str(&format!("[{}\\{}\\{}]\n", BTHPORT_KEYS, reg_name(adapter), reg_name(&info.mac)));
+
+    entry.push_str(&format!("\"LTK\"=hex:{}\n", reg_hex(&hex_bytes(&info.ltk))));
+    entry.push_str(&format!("\"KeyLength\"=dword:{:08x}\n", info.enc_size.parse::<u32>().unwrap_or(16)));
+    entry.push_str(&format!("\"ERand\"=hex(b):{}\n", reg_hex(&info.erand.parse::<u64>().unwrap_or(0).to_le_bytes())));
+    entry.push_str(&format!("\"EDIV\"=dword:{:08x}\n", info.ediv.parse::<u32>().unwrap_or(0)));
+
+    if !info.irk.is_empty() {
+        let mut irk = hex_bytes(&info.irk);
+        irk.reverse();
+        entry.push_str(&format!("\"IRK\"=hex:{}\n", reg_hex(&irk)));
+    }
+
+    let mut address = hex_bytes(&reg_name(&info.mac));
+    address.reverse();
+    address.resize(8, 0);
+    entry.push_str(&format!("\"Address\"=hex(b):{}\n", reg_hex(&address)));
+    entry.push_str(&format!("\"AddressType\"=dword:{:08x}\n", u32::from(info.address_type != "public")));
+
+    entry
+}
+
+/// Deletes the link key value and LE subkey `info` has under `adapter`.
+fn reg_device_removal(adapter: &str, info: &BtDeviceInfo) -> String {
+    let mut removal = String::new();
+    if !info.link_key.is_empty() {
+        removal.push_str(&format!("[{}\\{}]\n\"{}\"=-\n\n", BTHPORT_KEYS, reg_name(adapter), reg_name(&info.mac)));
+    }
+    if !info.ltk.is_empty() {
+        removal.push_str(&format!("[-{}\\{}\\{}]\n\n", BTHPORT_KEYS, reg_name(adapter), reg_name(&info.mac)));
+    }
+    removal
+}
+
+pub fn migrate_adapter_reg(from: &str, to: &str, bt_device_info: &DeviceMap) -> String {
+    let mut content = String::from("Windows Registry Editor Version 5.00\n");
+
+    let mut devices: Vec<&BtDeviceInfo> = bt_device_info.values()
+        .filter(|info| info.adapter == from)
+        .collect();
+    devices.sort_by(|a, b| a.mac.cmp(&b.mac));
+
+    for info in devices {
+        content.push_str(&format!("\n; {}\n", info.name));
+        content.push_str(&reg_device_removal(from, info));
+        content.push_str(&reg_device_entry(to, info));
+    }
+
+    content
+}
+
+/// Replaces the Windows pairing of each device with the newer Linux one.
+pub fn windows_update_reg(decisions: &[SyncDecision]) -> String {
+    let mut content = String::from("Windows Registry Editor Version 5.00\n");
+
+    for decision in decisions {
+        content.push_str(&format!("\n; {}\n", decision.name));
+        content.push_str(&reg_device_removal(&decision.windows.adapter, &decision.windows));
+        content.push_str(&reg_device_entry(&decision.linux.adapter, &decision.linux));
+    }
+
+    content
+}
+
+/// Adds every pairing in `bt_device_info`, replacing the values of keys that already exist.
+pub fn pairings_reg(bt_device_info: &DeviceMap) -> String {
+    let mut content = String::from("Windows Registry Editor Version 5.00\n");
+
+    let mut devices: Vec<&BtDeviceInfo> = bt_device_info.values().collect();
+    devices.sort_by(|a, b| (&a.adapter, &a.mac).cmp(&(&b.adapter, &b.mac)));
+
+    for info in devices {
+        content.push_str(&format!("\n; {}\n", info.name));
+        content.push_str(&reg_device_entry(&info.adapter, info));
+    }
+
+    content
+}
+
+/// regedit expects UTF-16LE with a byte order mark and CRLF line endings.
+pub fn encode_reg(content: &str) -> Vec<u8> {
+    let mut bytes = vec![0xff, 0xfe];
+    for unit in content.replace('\n', "\r\n").encode_utf16() {
+        bytes.extend_from_slice(&unit.to_le_bytes());
+    }
+    bytes
+}
+
+/// Reads a `.reg` file as regedit writes it, UTF-16LE with a byte order mark, or as UTF-8.
+pub fn decode_reg(bytes: &[u8]) -> String {
+    match bytes {
+        [0xff, 0xfe, rest @ ..] => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        [0xef, 0xbb, 0xbf, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// The bytes of a `hex:`, `hex(b):`, `dword:` or string value, `None` for deletions.
+fn reg_value_bytes(value: &str) -> Option<Vec<u8>> {
+    if let Some(dword) = value.strip_prefix("dword:") {
+        return u32::from_str_radix(dword, 16).ok().map(|v| v.to_le_bytes().to_vec());
+    }
+    if let Some(string) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return Some(string.replace("\\\\", "\\").into_bytes());
+    }
+    let (_, data) = value.split_once(':').filter(|(kind, _)| kind.starts_with("hex"))?;
+    data.split(',')
+        .map(|byte| byte.trim())
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect()
+}
+
+pub(crate) fn hex_upper(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+pub(crate) fn le_number(bytes: &[u8]) -> u64 {
+    bytes.iter().take(8).rev().fold(0, |n, &b| n << 8 | u64::from(b))
+}
+
+/// Joins the lines regedit wraps with a trailing backslash.
+fn reg_lines(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for line in content.lines() {
+        let line = if current.is_empty() { line.trim_end() } else { line.trim() };
+        match line.strip_suffix('\\') {
+            Some(head) if !line.starts_with('[') => current.push_str(head),
+            _ => {
+                current.push_str(line);
+                lines.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// A pairing being read from a `.reg` file, with the `; name` comment it appeared under.
+type RegDevice = ((String, String), String, BtDeviceInfo);
+
+/// The record for an (adapter, address) pair, created on first use.
+fn device_entry<'a>(devices: &'a mut Vec<RegDevice>, adapter: &str, mac: &str, comment: &str) -> &'a mut BtDeviceInfo {
+    let id = (fmt_mac(adapter), fmt_mac(mac));
+    let index = match devices.iter().position(|(known, _, _)| *known == id) {
+        Some(index) => index,
+        None => {
+            devices.push((id.clone(), comment.to_string(), BtDeviceInfo {
+                adapter: id.0,
+                mac: id.1,
+                address_type: "public".to_string(),
+                ..Default::default()
+            }));
+            devices.len() - 1
+        }
+    };
+    &mut devices[index].2
+}
+
+/// Reads the pairings a `.reg` file adds under `BTHPORT\Parameters`, from a regedit export or
+/// a file bt-sync wrote. Names come from `Devices` keys or the `; name` comments bt-sync writes.
+pub fn parse_reg_file(content: &str) -> DeviceMap {
+    let mut devices: Vec<RegDevice> = Vec::new();
+    let mut local_irks: HashMap<String, String> = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut comment = String::new();
+    let mut key: Vec<String> = Vec::new();
+
+    for line in reg_lines(content) {
+        if let Some(text) = line.strip_prefix(';') {
+            comment = text.trim().to_string();
+            continue;
+        }
+        if let Some(path) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // Keys being deleted and keys outside BTHPORT are not pairings.
+            key = path.to_lowercase()
+                .split_once(r"\services\bthport\parameters\")
+                .filter(|_| !path.starts_with('-'))
+                .map(|(_, rest)| rest.split('\\').map(str::to_string).collect())
+                .unwrap_or_default();
+            continue;
+        }
+        let Some((name, value)) = line.strip_prefix('"').and_then(|l| l.split_once("\"=")) else { continue };
+        let Some(data) = reg_value_bytes(value.trim()) else { continue };
+
+        match key.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+            ["keys", adapter] => match name {
+                "CentralIRK" | "MasterIRK" => { local_irks.insert(fmt_mac(adapter), hex_upper(&data.iter().rev().copied().collect::<Vec<u8>>())); }
+                mac if mac.len() == 12 && mac.chars().all(|c| c.is_ascii_hexdigit()) => device_entry(&mut devices, adapter, mac, &comment).link_key = hex_upper(&data),
+                _ => {}
+            },
+            ["keys", adapter, mac] => {
+                let info = device_entry(&mut devices, adapter, mac, &comment);
+                match name {
+                    "LTK" => info.ltk = hex_upper(&data),
+                    "IRK" => info.irk = hex_upper(&data.iter().rev().copied().collect::<Vec<u8>>()),
+                    "ERand" => info.erand = le_number(&data).to_string(),
+                    "EDIV" => info.ediv = le_number(&data).to_string(),
+                    "KeyLength" => info.enc_size = le_number(&data).to_string(),
+                    "AddressType" => info.address_type = if le_number(&data) == 0 { "public" } else { "static" }.to_string(),
+                    _ => {}
+                }
+            }
+            ["devices", mac] if name == "Name" => { names.insert(fmt_mac(mac), decode_name(&data, &fmt_mac(mac))); }
+            _ => {}
+        }
+    }
+
+    let mut bt_device_info = DeviceMap::new();
+    for ((adapter, mac), comment, mut info) in devices {
+        if info.ltk.is_empty() && info.link_key.is_empty() {
+            continue;
+        }
+        if info.ltk.is_empty() {
+            info.address_type = "public".to_string();
+        } else if info.enc_size.is_empty() {
+            info.enc_size = "16".to_string();
+        }
+        info.local_irk = local_irks.get(&adapter).cloned().unwrap_or_default();
+        info.name = names.get(&mac).filter(|name| !name.is_empty()).cloned()
+            .or_else(|| (!comment.is_empty()).then_some(comment))
+            .unwrap_or_else(|| mac.clone());
+        bt_device_info.insert(info.key(), info);
+    }
+    bt_device_info
+}
+
+/// A `.reg` file: read as a source, written for import on Windows as a sink.
+pub struct RegFile {
+    pub path: PathBuf,
+}
+
+impl PairingSource for RegFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read(&self) -> Result<DeviceMap> {
+        let bytes = fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let mut bt_device_info = parse_reg_file(&decode_reg(&bytes));
+        for info in bt_device_info.values_mut() {
+            info.source = self.path.display().to_string();
+        }
+        Ok(bt_device_info)
+    }
+}
+
+impl PairingSink for RegFile {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool> {
+        let bt_device_info = map_adapters(bt_device_info, &[], options);
+        write_atomic(&self.path, encode_reg(&pairings_reg(&bt_device_info)))?;
+        println!("{}", green!("\n=== {} device(s) written to {}, import it on Windows ===", bt_device_info.len(), self.path.display()));
+        Ok(true)
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+
+use crate::{
+    android::AndroidConfig,
+    bluetooth::{device_map, BtDeviceInfo, DeviceMap, SyncOptions},
+    bluez::{is_same_directory, BluezTree, OtherLinux},
+    btsnoop::BtsnoopFile,
+    bundle::{EncryptedBundle, JsonFile},
+    hive::HiveFile,
+    macos::PlistFile,
+    partitions::WindowsInstalls,
+    regfile::RegFile,
+};
+
+/// Somewhere pairings can be read from.
+pub trait PairingSource {
+    /// The partition or file read, for messages.
+    fn describe(&self) -> String;
+    /// Devices by adapter and address.
+    fn read(&self) -> Result<DeviceMap>;
+}
+
+/// Somewhere pairings can be written to.
+pub trait PairingSink {
+    fn describe(&self) -> String;
+    /// Writes `bt_device_info` and returns whether anything changed.
+    fn write(&self, bt_device_info: &DeviceMap, options: &SyncOptions) -> Result<bool>;
+}
+
+/// A pairing store named on the command line as `<kind>:<path>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreSpec {
+    /// A Windows `SYSTEM` hive file.
+    Hive(PathBuf),
+    /// A `.reg` file as regedit imports and exports it.
+    Reg(PathBuf),
+    /// A `/var/lib/bluetooth` style directory.
+    Bluez(PathBuf),
+    Json(PathBuf),
+    /// A passphrase-encrypted JSON file.
+    Bundle(PathBuf),
+    /// macOS's `com.apple.bluetoothd.plist`, only readable.
+    Plist(PathBuf),
+    /// Android's `bt_config.conf`, or a fragment of it.
+    Android(PathBuf),
+    /// A btsnoop capture taken while devices were paired, only readable.
+    Btsnoop(PathBuf),
+}
+
+impl StoreSpec {
+    pub fn parse(value: &str) -> Result<StoreSpec> {
+        let Some((kind, path)) = value.split_once(':').filter(|(_, path)| !path.is_empty()) else {
+            bail!("Expected <hive|reg|bluez|json|bundle|plist|android|btsnoop>:<path>, got {}", value);
+        };
+        let path = PathBuf::from(path);
+        Ok(match kind {
+            "hive" => StoreSpec::Hive(path),
+            "reg" => StoreSpec::Reg(path),
+            "bluez" => StoreSpec::Bluez(path),
+            "json" => StoreSpec::Json(path),
+            "bundle" => StoreSpec::Bundle(path),
+            "plist" => StoreSpec::Plist(path),
+            "android" => StoreSpec::Android(path),
+            "btsnoop" => StoreSpec::Btsnoop(path),
+            other => bail!("Unknown pairing store: {}", other),
+        })
+    }
+}
+
+/// The source the options ask for: `--source`, `--from-linux`, or every Windows install.
+/// The running system's BlueZ state is only a source for `export`.
+pub fn open_source(bt_dir_path: &str, options: &SyncOptions) -> Result<Box<dyn PairingSource>> {
+    Ok(match (&options.source, &options.from_linux) {
+        (Some(StoreSpec::Bluez(path)), _) if !options.export && is_same_directory(path, Path::new(bt_dir_path)) => {
+            bail!("{} is the running system's Bluetooth state, which only export can read", path.display())
+        }
+        (Some(StoreSpec::Hive(path)), _) => Box::new(HiveFile { path: path.clone() }),
+        (Some(StoreSpec::Reg(path)), _) => Box::new(RegFile { path: path.clone() }),
+        (Some(StoreSpec::Bluez(path)), _) => Box::new(BluezTree { path: path.clone() }),
+        (Some(StoreSpec::Json(path)), _) => Box::new(JsonFile { path: path.clone() }),
+        (Some(StoreSpec::Bundle(path)), _) => Box::new(EncryptedBundle { path: path.clone(), passphrase: None }),
+        (Some(StoreSpec::Plist(path)), _) => Box::new(PlistFile { path: path.clone() }),
+        (Some(StoreSpec::Android(path)), _) => Box::new(AndroidConfig { path: path.clone() }),
+        (Some(StoreSpec::Btsnoop(path)), _) => Box::new(BtsnoopFile { path: path.clone() }),
+        (None, Some(source)) => Box::new(OtherLinux { source: source.clone(), running: PathBuf::from(bt_dir_path) }),
+        (None, None) => Box::new(WindowsInstalls { aggregate: options.aggregate.clone() }),
+    })
+}
+
+/// The sink the options ask for, the running system's BlueZ state by default.
+pub fn open_sink(bt_dir_path: &str, options: &SyncOptions) -> Result<Box<dyn PairingSink>> {
+    Ok(match &options.sink {
+        Some(StoreSpec::Hive(path)) => Box::new(HiveFile { path: path.clone() }),
+        Some(StoreSpec::Reg(path)) => Box::new(RegFile { path: path.clone() }),
+        Some(StoreSpec::Bluez(path)) => Box::new(BluezTree { path: path.clone() }),
+        Some(StoreSpec::Json(path)) => Box::new(JsonFile { path: path.clone() }),
+        Some(StoreSpec::Bundle(path)) => Box::new(EncryptedBundle { path: path.clone(), passphrase: None }),
+        Some(StoreSpec::Plist(_)) => bail!("macOS plists can only be read, not written"),
+        Some(StoreSpec::Android(path)) => Box::new(AndroidConfig { path: path.clone() }),
+        Some(StoreSpec::Btsnoop(_)) => bail!("btsnoop captures can only be read, not written"),
+        None => Box::new(BluezTree { path: PathBuf::from(bt_dir_path) }),
+    })
+}
+
+/// Reads every pairing from `source` and hands it to `sink`.
+pub fn sync_pairings(source: &dyn PairingSource, sink: &dyn PairingSink, options: &SyncOptions) -> Result<bool> {
+    let bt_device_info = source.read()?;
+    if bt_device_info.is_empty() {
+        eprintln!("No pairings found in {}.", source.describe());
+        return Ok(false);
+    }
+    sink.write(&bt_device_info, options)
+}
+
+/// Renames adapters for a sink that keys pairings by adapter: `--adapter-map` first,
+/// otherwise a single adapter on each side is taken to be the same controller.
+pub(crate) fn map_adapters(
+    bt_device_info: &DeviceMap,
+    sink_adapters: &[String],
+    options: &SyncOptions
+) -> DeviceMap {
+    let mut source_adapters: Vec<&String> = bt_device_info.values().map(|info| &info.adapter).collect();
+    source_adapters.sort();
+    source_adapters.dedup();
+    let single = options.adapter_map.is_empty() && source_adapters.len() == 1 && sink_adapters.len() == 1;
+
+    device_map(bt_device_info.values().map(|info| {
+        let adapter = match options.adapter_map.get(&info.adapter) {
+            Some(adapter) => adapter.clone(),
+            None if single => sink_adapters[0].clone(),
+            None => info.adapter.clone(),
+        };
+        BtDeviceInfo { adapter, ..info.clone() }
+    }))
+}
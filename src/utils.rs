@@ -1,3 +1,4 @@
+use std::{fs, io::{self, Write}, os::unix::fs::{chown, MetadataExt, PermissionsExt}, path::Path, time::{SystemTime, UNIX_EPOCH}};
 use term_ansi::*;
 
 
@@ -62,9 +63,74 @@ pub fn restart_with_sudo() {
     let current_exe = env::current_exe().expect("Failed to get current executable path");
     let args: Vec<String> = env::args().collect();
 
-    Command::new("sudo")
+    let status = Command::new("sudo")
         .arg(current_exe)
         .args(&args[1..])
         .status()
         .expect("Failed to execute sudo");
+
+    // Pass the exit code on, `status` reports out-of-sync devices through it.
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Replaces `path` through a synced temp file in the same directory, so a crash
+/// leaves either the old or the new content. An existing file keeps its mode and
+/// owner, a new one is created root-only like the rest of /var/lib/bluetooth.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) -> io::Result<()> {
+    let path = path.as_ref();
+    // A bare file name has an empty parent.
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(content.as_ref())?;
+
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            fs::set_permissions(tmp.path(), fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+            chown(tmp.path(), Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+        Err(_) => fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o600))?,
+    }
+
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    sync_dir(dir)
+}
+
+/// Renames a file or directory and syncs both parent directories.
+pub fn rename_durable<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    fs::rename(from, to)?;
+
+    if let Some(dir) = to.parent() {
+        sync_dir(dir)?;
+    }
+    match from.parent() {
+        Some(dir) if Some(dir) != to.parent() => sync_dir(dir),
+        _ => Ok(()),
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Formats a time as `YYYY-MM-DD HH:MM:SS UTC` for reports.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil inverse.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
 }
@@ -1,12 +1,27 @@
 use bluetooth::*;
 use bt_sync::*;
 use hive::parse_reg;
-use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::tempdir;
 use anyhow::Result;
 
+/// A Secure Connections LE pairing as Windows reports it, on the adapter the fixtures use.
+fn windows_device(name: &str, mac: &str, ltk: &str) -> BtDeviceInfo {
+    BtDeviceInfo {
+        name: name.to_string(),
+        adapter: "74:4C:A1:54:4B:8E".to_string(),
+        mac: mac.to_string(),
+        ltk: ltk.to_string(),
+        ediv: "0".to_string(),
+        erand: "0".to_string(),
+        address_type: "static".to_string(),
+        enc_size: "16".to_string(),
+        ..Default::default()
+    }
+}
+
 #[test]
 fn test_fmt_mac() {
     assert_eq!(fmt_mac("001122334455"), "00:11:22:33:44:55");
@@ -22,9 +37,10 @@ Name=test"#;
         mac: "00:00:00:00:00:00".to_string(),
         ltk: "112233445566778899AABBCCDDEEFF".to_string(),
         ediv: "12345".to_string(),
-        erand: "998877665544".to_string()
+        erand: "998877665544".to_string(),
+        ..Default::default()
     };
-    let updated_content = update_bt_info(content, &info);
+    let updated_content = update_bt_info(content, &info, PeripheralKeySection::Peripheral);
     assert!(updated_content.contains(&format!("Key={}", info.ltk)));
     Ok(())
 }
@@ -86,20 +102,20 @@ Version=1"#;
 
     println!("PP={}", info_path.to_string_lossy());
 
-    let mut bt_device_info: HashMap<String, BtDeviceInfo> = HashMap::new();
     let new_ltk = "DEADBEEF00000000DEADBEEF00000000";
-    bt_device_info.insert(
-        "Basilisk X HyperSpeed".to_string(),
+    let bt_device_info = device_map([
         BtDeviceInfo {
+            name: "Basilisk X HyperSpeed".to_string(),
             mac: "00:11:22:33:44:55".to_string(),
             ltk: new_ltk.to_string(),
             ediv: "12345".to_string(),
-            erand: "998877665544".to_string()
+            erand: "998877665544".to_string(),
+            ..Default::default()
         }
-    );
+    ]);
 
     std::env::set_var("TESTING", "true");
-    process_bth_device(temp_dir.path().to_path_buf(), &bt_device_info)?;
+    process_bth_device(temp_dir.path().to_path_buf(), &bt_device_info, &SyncOptions::default())?;
 
     let new_dir = dir.parent().unwrap().join("00:11:22:33:44:55");
     assert!(new_dir.exists());
@@ -120,32 +136,1613 @@ fn test_parse_reg() -> Result<()> {
 
     let result = parse_reg("/dev/test", path.to_str().unwrap())?;
     
-    let expected_map: HashMap<String, BtDeviceInfo> = [
-        ("BT+2.4G KB".to_string(), BtDeviceInfo {
+    // The fixture hive was last written on 2024-12-05.
+    let written = Some(UNIX_EPOCH + Duration::new(1733408012, 510863100));
+    let expected_map = device_map([
+        BtDeviceInfo {
+            name: "BT+2.4G KB".to_string(),
+            adapter: "74:4C:A1:54:4B:8E".to_string(),
             mac: "E0:10:5F:A9:F6:59".to_string(),
             ltk: "039D9DE0952391208B4F755257E6425B".to_string(),
             ediv: "28781".to_string(),
-            erand: "16975003643600944841".to_string()
-        }),
+            erand: "16975003643600944841".to_string(),
+            irk: "47A9EE389A8CCF8B5C76E95CE2E87E65".to_string(),
+            address_type: "static".to_string(),
+            enc_size: "16".to_string(),
+            local_irk: "514EAE22DDAFF601CFFEE74B2E029AD5".to_string(),
+            last_written: written,
+            source: "/dev/test".to_string(),
+            appearance: "0x03c1".to_string(),
+            device_id_source: "2".to_string(),
+            vendor: "39658".to_string(),
+            product: "4137".to_string(),
+            version: "1".to_string(),
+            ..Default::default()
+        },
 
-        ("Basilisk X HyperSpeed".to_string(), BtDeviceInfo {
+        BtDeviceInfo {
+            name: "Basilisk X HyperSpeed".to_string(),
+            adapter: "74:4C:A1:54:4B:8E".to_string(),
             mac: "FC:51:CA:AC:57:11".to_string(),
             ltk: "D23FEDC5F5806AF8A37D41D81EE4DA5C".to_string(),
             ediv: "34794".to_string(),
-            erand: "9659891662176722970".to_string()
-        }),
+            erand: "9659891662176722970".to_string(),
+            irk: "8EC94951919F694C8DBFD5E0BEA21536".to_string(),
+            address_type: "static".to_string(),
+            enc_size: "16".to_string(),
+            local_irk: "514EAE22DDAFF601CFFEE74B2E029AD5".to_string(),
+            last_written: written,
+            source: "/dev/test".to_string(),
+            appearance: "0x03c2".to_string(),
+            device_id_source: "2".to_string(),
+            vendor: "5426".to_string(),
+            product: "130".to_string(),
+            version: "1".to_string(),
+            ..Default::default()
+        },
         
-        ("Xbox Wireless Controller".to_string(), BtDeviceInfo {
+        BtDeviceInfo {
+            name: "Xbox Wireless Controller".to_string(),
+            adapter: "74:4C:A1:54:4B:8E".to_string(),
             mac: "AC:8E:BD:24:AC:52".to_string(),
             ltk: "84417A06F13444B2780E0CC3CF1D353D".to_string(),
             ediv: "0".to_string(),
-            erand: "0".to_string()
-        })
-    ]
-    .iter()
-    .cloned()
-    .collect();
+            erand: "0".to_string(),
+            irk: "763752AC24BD8EAC43DAEAEBCCED3776".to_string(),
+            address_type: "public".to_string(),
+            enc_size: "16".to_string(),
+            local_irk: "514EAE22DDAFF601CFFEE74B2E029AD5".to_string(),
+            last_written: written,
+            source: "/dev/test".to_string(),
+            appearance: "0x03c4".to_string(),
+            device_id_source: "2".to_string(),
+            vendor: "1118".to_string(),
+            product: "2835".to_string(),
+            version: "1289".to_string(),
+            ..Default::default()
+        }
+    ]);
     assert_eq!(expected_map, result);
 
     Ok(())
 }
+
+#[test]
+fn test_import_bth_device() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let dir = temp_dir.path().join("00:00:00:00:00:00");
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("info"), "[General]\nName=Basilisk X HyperSpeed\n")?;
+
+    let bt_device_info = device_map([
+        BtDeviceInfo { name: "Basilisk X HyperSpeed".to_string(), mac: "FC:51:CA:AC:57:11".to_string(), ..Default::default() },
+        BtDeviceInfo {
+            name: "Xbox Wireless Controller".to_string(),
+            mac: "AC:8E:BD:24:AC:52".to_string(),
+            ltk: "84417A06F13444B2780E0CC3CF1D353D".to_string(),
+            ediv: "0".to_string(),
+            erand: "0".to_string(),
+            irk: "763752AC24BD8EAC43DAEAEBCCED3776".to_string(),
+            address_type: "public".to_string(),
+            enc_size: "16".to_string(),
+            ..Default::default()
+        },
+    ]);
+
+    assert!(import_bth_device(temp_dir.path().to_path_buf(), &bt_device_info, &SyncOptions::default())?);
+
+    assert!(!temp_dir.path().join("FC:51:CA:AC:57:11").exists());
+
+    let content = fs::read_to_string(temp_dir.path().join("AC:8E:BD:24:AC:52").join("info"))?;
+    assert!(content.contains("Name=Xbox Wireless Controller"));
+    assert!(content.contains("AddressType=public"));
+    assert!(content.contains("Trusted=true"));
+    assert!(content.contains("[IdentityResolvingKey]\nKey=763752AC24BD8EAC43DAEAEBCCED3776"));
+    assert_eq!(get_ltk(&content), "84417A06F13444B2780E0CC3CF1D353D");
+
+    let cache = fs::read_to_string(temp_dir.path().join("cache").join("AC:8E:BD:24:AC:52"))?;
+    assert!(cache.contains("Name=Xbox Wireless Controller"));
+
+    assert!(!import_bth_device(temp_dir.path().to_path_buf(), &bt_device_info, &SyncOptions::default())?);
+
+    Ok(())
+}
+
+#[test]
+fn test_adapter_mapping() -> Result<()> {
+    let bt_device_info = device_map([
+        BtDeviceInfo {
+            name: "Dongle Mouse".to_string(),
+            adapter: "00:1A:7D:DA:71:13".to_string(),
+            mac: "11:11:11:11:11:11".to_string(),
+            ..Default::default()
+        },
+        BtDeviceInfo {
+            name: "Builtin Keyboard".to_string(),
+            adapter: "74:4C:A1:54:4B:8E".to_string(),
+            mac: "22:22:22:22:22:22".to_string(),
+            ..Default::default()
+        },
+    ]);
+
+    let linux_adapters = vec!["00:1A:7D:DA:71:13".to_string(), "74:4C:A1:54:4B:8F".to_string()];
+
+    let adapter_map = resolve_adapter_map(&bt_device_info, &linux_adapters, &SyncOptions::default());
+    let devices = devices_for_adapter("00:1A:7D:DA:71:13", &bt_device_info, &adapter_map);
+    assert_eq!(devices.values().map(|info| info.name.as_str()).collect::<Vec<_>>(), vec!["Dongle Mouse"]);
+    assert!(devices_for_adapter("74:4C:A1:54:4B:8F", &bt_device_info, &adapter_map).is_empty());
+
+    let args: Vec<String> = ["sync", "--adapter-map", "744ca1544b8e=74:4c:a1:54:4b:8f"].iter().map(|s| s.to_string()).collect();
+    let options = match cli::parse_args(&args)? {
+        cli::Command::Sync(options) => options,
+        command => panic!("unexpected command {:?}", command),
+    };
+    let adapter_map = resolve_adapter_map(&bt_device_info, &linux_adapters, &options);
+    let devices = devices_for_adapter("74:4C:A1:54:4B:8F", &bt_device_info, &adapter_map);
+    assert_eq!(devices.values().map(|info| info.name.as_str()).collect::<Vec<_>>(), vec!["Builtin Keyboard"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_adapter() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let from = temp_dir.path().join("74:4C:A1:54:4B:8E");
+    let to = temp_dir.path().join("00:1A:7D:DA:71:13");
+    fs::create_dir_all(from.join("AC:8E:BD:24:AC:52"))?;
+    fs::create_dir_all(from.join("FC:51:CA:AC:57:11"))?;
+    fs::create_dir_all(from.join("cache"))?;
+    fs::create_dir_all(to.join("FC:51:CA:AC:57:11"))?;
+    fs::write(from.join("AC:8E:BD:24:AC:52").join("info"), "[General]\nName=Xbox Wireless Controller\n")?;
+    fs::write(from.join("cache").join("AC:8E:BD:24:AC:52"), "[General]\nName=Xbox Wireless Controller\n")?;
+    fs::write(from.join("settings"), "[General]\nDiscoverableTimeout=0\n")?;
+
+    let results = migrate_adapter(temp_dir.path(), "74:4C:A1:54:4B:8E", "00:1A:7D:DA:71:13")?;
+
+    assert!(to.join("AC:8E:BD:24:AC:52").join("info").exists());
+    assert!(to.join("cache").join("AC:8E:BD:24:AC:52").exists());
+    assert!(to.join("settings").exists());
+    assert!(from.join("FC:51:CA:AC:57:11").exists());
+    assert!(results.contains(&("FC:51:CA:AC:57:11".to_string(), "skipped, already paired on 00:1A:7D:DA:71:13".to_string())));
+    assert!(results.contains(&("settings".to_string(), "moved".to_string())));
+
+    // Settings the new adapter already has are kept, like its pairings.
+    fs::write(from.join("settings"), "[General]\nDiscoverableTimeout=0\n")?;
+    fs::write(to.join("settings"), "[General]\nDiscoverableTimeout=180\n")?;
+    let results = migrate_adapter(temp_dir.path(), "74:4C:A1:54:4B:8E", "00:1A:7D:DA:71:13")?;
+    assert_eq!(fs::read_to_string(to.join("settings"))?, "[General]\nDiscoverableTimeout=180\n");
+    assert!(from.join("settings").exists());
+    assert!(results.contains(&("settings".to_string(), "skipped, already set on 00:1A:7D:DA:71:13".to_string())));
+
+    let path = Path::new(file!()).parent().unwrap().join("data");
+    let bt_device_info = parse_reg("/dev/test", path.to_str().unwrap())?;
+    let reg = regfile::migrate_adapter_reg("74:4C:A1:54:4B:8E", "00:1A:7D:DA:71:13", &bt_device_info);
+    let keys = r"HKEY_LOCAL_MACHINE\SYSTEM\CurrentControlSet\Services\BTHPORT\Parameters\Keys";
+    assert!(reg.starts_with("Windows Registry Editor Version 5.00\n"));
+    assert!(reg.contains(&format!("[-{}\\744ca1544b8e\\ac8ebd24ac52]", keys)));
+    assert!(reg.contains(&format!("[{}\\001a7dda7113\\ac8ebd24ac52]", keys)));
+    assert!(reg.contains("\"LTK\"=hex:84,41,7a,06,f1,34,44,b2,78,0e,0c,c3,cf,1d,35,3d\n"));
+    assert!(reg.contains("\"IRK\"=hex:76,37,ed,cc,eb,ea,da,43,ac,8e,bd,24,ac,52,37,76\n"));
+    assert!(reg.contains("\"ERand\"=hex(b):c9,be,cb,5d,e0,53,93,eb\n"));
+    assert!(reg.contains("\"Address\"=hex(b):52,ac,24,bd,8e,ac,00,00\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_follows_renamed_device() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let cache = temp_dir.path().join("cache");
+    fs::create_dir_all(temp_dir.path().join("00:00:00:00:00:01"))?;
+    fs::create_dir_all(temp_dir.path().join("00:00:00:00:00:02"))?;
+    fs::create_dir_all(&cache)?;
+    fs::write(temp_dir.path().join("00:00:00:00:00:01").join("info"), "[General]\nName=Basilisk X HyperSpeed\n")?;
+    fs::write(temp_dir.path().join("00:00:00:00:00:02").join("info"), "[General]\nName=BT+2.4G KB\n")?;
+    fs::write(cache.join("00:00:00:00:00:01"), "[General]\n\n[Attributes]\n0x0001=2800:0x0005:1800\n")?;
+    fs::write(cache.join("00:00:00:00:00:02"), "[General]\nName=Old KB\n\n[Attributes]\n0x0001=2800:0x0007:1800\n")?;
+    fs::write(cache.join("E0:10:5F:A9:F6:59"), "[General]\nName=Old KB\n\n[ServiceRecords]\n0x00010000=3601\n")?;
+
+    let bt_device_info = device_map([
+        BtDeviceInfo { name: "Basilisk X HyperSpeed".to_string(), mac: "FC:51:CA:AC:57:11".to_string(), ..Default::default() },
+        BtDeviceInfo { name: "BT+2.4G KB".to_string(), mac: "E0:10:5F:A9:F6:59".to_string(), ..Default::default() },
+    ]);
+
+    process_bth_device(temp_dir.path().to_path_buf(), &bt_device_info, &SyncOptions::default())?;
+
+    assert!(!cache.join("00:00:00:00:00:01").exists());
+    assert!(!cache.join("00:00:00:00:00:02").exists());
+
+    let moved = fs::read_to_string(cache.join("FC:51:CA:AC:57:11"))?;
+    assert!(moved.contains("Name=Basilisk X HyperSpeed"));
+    assert!(moved.contains("0x0001=2800:0x0005:1800"));
+
+    let merged = fs::read_to_string(cache.join("E0:10:5F:A9:F6:59"))?;
+    assert!(merged.contains("Name=BT+2.4G KB"));
+    assert!(!merged.contains("Name=Old KB"));
+    assert!(merged.contains("0x0001=2800:0x0007:1800"));
+    assert!(merged.contains("[ServiceRecords]\n0x00010000=3601"));
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_collision_policies() -> Result<(), Box<dyn std::error::Error>> {
+    let bt_device_info = device_map([BtDeviceInfo {
+        name: "BT+2.4G KB".to_string(),
+        mac: "E0:10:5F:A9:F6:59".to_string(),
+        ltk: "039D9DE0952391208B4F755257E6425B".to_string(),
+        ..Default::default()
+    }]);
+    let old_info = "[General]\nName=BT+2.4G KB\n\n[LongTermKey]\nKey=00000000000000000000000000000000\n";
+    let existing_info = "[General]\nName=BT+2.4G KB\n\n[LongTermKey]\nKey=11111111111111111111111111111111\n\n[DeviceID]\nVendor=39658\n";
+
+    // Directories are visited in address order, so the stale one is seen before or after the current one.
+    let policies = [CollisionPolicy::Merge, CollisionPolicy::Skip, CollisionPolicy::Replace];
+    for (old_mac, policy) in ["00:00:00:00:00:01", "F0:00:00:00:00:01"].into_iter().flat_map(|mac| policies.map(|policy| (mac, policy))) {
+        let temp_dir = tempdir()?;
+        let old_dir = temp_dir.path().join(old_mac);
+        let new_dir = temp_dir.path().join("E0:10:5F:A9:F6:59");
+        fs::create_dir_all(&old_dir)?;
+        fs::create_dir_all(&new_dir)?;
+        fs::write(old_dir.join("info"), old_info)?;
+        fs::write(old_dir.join("attributes"), "[0x0001]\n")?;
+        fs::write(new_dir.join("info"), existing_info)?;
+
+        let options = SyncOptions { on_collision: policy, ..Default::default() };
+        let results = update_devices(temp_dir.path(), &bt_device_info, &options)?;
+        let report: Vec<(&str, &str, &str)> = results.iter().map(|result| (result.old_mac.as_str(), result.new_mac.as_str(), result.note.as_str())).collect();
+        assert!(results.iter().all(|result| result.name == "BT+2.4G KB"));
+        let in_place = ("E0:10:5F:A9:F6:59", "E0:10:5F:A9:F6:59", "");
+
+        let content = fs::read_to_string(new_dir.join("info"))?;
+        let collision = match policy {
+            CollisionPolicy::Merge => {
+                assert!(!old_dir.exists());
+                assert_eq!(get_ltk(&content), "039D9DE0952391208B4F755257E6425B");
+                assert!(content.contains("[DeviceID]\nVendor=39658"));
+                assert!(new_dir.join("attributes").exists());
+                (old_mac, "E0:10:5F:A9:F6:59", "merged into existing E0:10:5F:A9:F6:59")
+            }
+            CollisionPolicy::Skip => {
+                assert_eq!(fs::read_to_string(old_dir.join("info"))?, old_info);
+                assert_eq!(get_ltk(&content), "039D9DE0952391208B4F755257E6425B");
+                assert!(content.contains("[DeviceID]\nVendor=39658"));
+                (old_mac, old_mac, "skipped, E0:10:5F:A9:F6:59 already exists")
+            }
+            CollisionPolicy::Replace => {
+                assert!(!old_dir.exists());
+                assert_eq!(get_ltk(&content), "039D9DE0952391208B4F755257E6425B");
+                assert!(!content.contains("[DeviceID]"));
+                (old_mac, "E0:10:5F:A9:F6:59", "replaced existing E0:10:5F:A9:F6:59")
+            }
+        };
+        // A skipped directory leaves the current one to be updated in its own turn; a merge or
+        // replace consumes it when the stale directory comes first.
+        let expected = match (old_mac < "E0", policy) {
+            (true, CollisionPolicy::Skip) => vec![collision, in_place],
+            (true, _) => vec![collision],
+            (false, _) => vec![in_place, collision],
+        };
+        assert_eq!(report, expected, "{:?} with {} first", policy, if old_mac < "E0" { "the stale directory" } else { "the current one" });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_atomic_preserves_mode() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir()?;
+    let info_path = temp_dir.path().join("info");
+    fs::write(&info_path, "[General]\nName=old\n")?;
+    fs::set_permissions(&info_path, fs::Permissions::from_mode(0o640))?;
+
+    utils::write_atomic(&info_path, "[General]\nName=new\n")?;
+    assert_eq!(fs::read_to_string(&info_path)?, "[General]\nName=new\n");
+    assert_eq!(fs::metadata(&info_path)?.permissions().mode() & 0o777, 0o640);
+
+    let new_path = temp_dir.path().join("cache");
+    utils::write_atomic(&new_path, "[General]\n")?;
+    assert_eq!(fs::metadata(&new_path)?.permissions().mode() & 0o777, 0o600);
+
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_keyfile_roundtrip() {
+    let content = "# written by bluetoothd\n[General]\nName = Basilisk X HyperSpeed\n\n# keys\n[LongTermKey]   \nKey=D23FEDC5F5806AF8A37D41D81EE4DA5C\nEDiv=17209\n\n\n[DeviceID]\nVendor=5426";
+    let mut keyfile = keyfile::KeyFile::parse(content);
+    assert_eq!(keyfile.to_string(), content);
+
+    assert_eq!(keyfile.get("General", "Name"), Some("Basilisk X HyperSpeed"));
+    assert_eq!(keyfile.get("LongTermKey", "Key"), Some("D23FEDC5F5806AF8A37D41D81EE4DA5C"));
+    assert_eq!(keyfile.groups().collect::<Vec<_>>(), vec!["General", "LongTermKey", "DeviceID"]);
+
+    keyfile.set("LongTermKey", "EDiv", "17209");
+    assert_eq!(keyfile.to_string(), content);
+
+    keyfile.set("LongTermKey", "Rand", "0");
+    keyfile.remove("DeviceID", "Vendor");
+    keyfile.set("IdentityResolvingKey", "Key", "8EC94951919F694C8DBFD5E0BEA21536");
+    assert!(keyfile.remove_group("DeviceID"));
+    assert_eq!(
+        keyfile.to_string(),
+        "# written by bluetoothd\n[General]\nName = Basilisk X HyperSpeed\n\n# keys\n[LongTermKey]   \nKey=D23FEDC5F5806AF8A37D41D81EE4DA5C\nEDiv=17209\nRand=0\n\n\n[IdentityResolvingKey]\nKey=8EC94951919F694C8DBFD5E0BEA21536"
+    );
+
+    let content = "[General]\r\nName=Basilisk X HyperSpeed\r\n\r\n[LongTermKey]\r\nKey=D23FEDC5F5806AF8A37D41D81EE4DA5C\r\n";
+    let mut keyfile = keyfile::KeyFile::parse(content);
+    assert_eq!(keyfile.to_string(), content);
+    assert_eq!(keyfile.get("General", "Name"), Some("Basilisk X HyperSpeed"));
+    assert_eq!(keyfile.get("LongTermKey", "Key"), Some("D23FEDC5F5806AF8A37D41D81EE4DA5C"));
+
+    keyfile.set("LongTermKey", "Key", "84417A06F13444B2780E0CC3CF1D353D");
+    keyfile.set("LongTermKey", "Rand", "0");
+    keyfile.set("IdentityResolvingKey", "Key", "8EC94951919F694C8DBFD5E0BEA21536");
+    assert_eq!(
+        keyfile.to_string(),
+        "[General]\r\nName=Basilisk X HyperSpeed\r\n\r\n[LongTermKey]\r\nKey=84417A06F13444B2780E0CC3CF1D353D\r\nRand=0\r\n\r\n[IdentityResolvingKey]\r\nKey=8EC94951919F694C8DBFD5E0BEA21536\r\n"
+    );
+
+    // Removing a group takes its separator along, so repeated syncs do not add blank lines.
+    let synced = "[General]\nName=Basilisk X HyperSpeed\n\n[LongTermKey]\nKey=D23FEDC5F5806AF8A37D41D81EE4DA5C\n";
+    let mut content = format!("{}\n[PeripheralLongTermKey]\nKey=414C87970DBAE282734D2BDCC1157C30\n", synced);
+    for _ in 0..3 {
+        let mut keyfile = keyfile::KeyFile::parse(&content);
+        assert!(keyfile.remove_group("PeripheralLongTermKey"));
+        assert_eq!(keyfile.to_string(), synced);
+        keyfile.set("PeripheralLongTermKey", "Key", "414C87970DBAE282734D2BDCC1157C30");
+        content = keyfile.to_string();
+    }
+    let mut keyfile = keyfile::KeyFile::parse("[General]\nName=Mouse\n\n[SlaveLongTermKey]\nKey=0\n\n[DeviceID]\nVendor=5426\n");
+    assert!(keyfile.remove_group("SlaveLongTermKey"));
+    assert_eq!(keyfile.to_string(), "[General]\nName=Mouse\n\n[DeviceID]\nVendor=5426\n");
+
+    // A group written twice is one group; later values win and every copy is kept in step.
+    let content = "[General]\nName=Mouse\nTrusted=false\n\n[LinkKey]\nKey=0\n\n[General]\nTrusted=true\n";
+    let mut keyfile = keyfile::KeyFile::parse(content);
+    assert_eq!(keyfile.to_string(), content);
+    assert_eq!(keyfile.groups().collect::<Vec<_>>(), vec!["General", "LinkKey"]);
+    assert_eq!(keyfile.keys("General"), vec!["Name", "Trusted"]);
+    assert_eq!(keyfile.get("General", "Name"), Some("Mouse"));
+    assert_eq!(keyfile.get("General", "Trusted"), Some("true"));
+    keyfile.set("General", "Trusted", "true");
+    keyfile.set("General", "Blocked", "false");
+    assert_eq!(keyfile.to_string(), "[General]\nName=Mouse\nTrusted=true\n\n[LinkKey]\nKey=0\n\n[General]\nTrusted=true\nBlocked=false\n");
+    assert!(keyfile.remove("General", "Trusted"));
+    assert_eq!(keyfile.get("General", "Trusted"), None);
+    assert!(keyfile.remove_group("General"));
+    assert_eq!(keyfile.to_string(), "[LinkKey]\nKey=0\n");
+}
+
+#[test]
+fn test_update_bt_info_adds_missing_keys() {
+    let info = BtDeviceInfo {
+        ltk: "84417A06F13444B2780E0CC3CF1D353D".to_string(),
+        ediv: "0".to_string(),
+        erand: "0".to_string(),
+        ..Default::default()
+    };
+
+    let updated = update_bt_info("[General]\nName=Xbox Wireless Controller\n", &info, PeripheralKeySection::Peripheral);
+    assert_eq!(
+        updated,
+        "[General]\nName=Xbox Wireless Controller\n\n[LongTermKey]\nKey=84417A06F13444B2780E0CC3CF1D353D\nAuthenticated=2\nEncSize=16\nEDiv=0\nRand=0\n"
+    );
+
+    let updated = update_bt_info("[LongTermKey] \nKey=00000000000000000000000000000000\nEncSize=16", &info, PeripheralKeySection::Peripheral);
+    assert_eq!(get_ltk(&updated), info.ltk);
+    assert_eq!(
+        updated,
+        "[LongTermKey] \nKey=84417A06F13444B2780E0CC3CF1D353D\nEncSize=16\nAuthenticated=2\nEDiv=0\nRand=0"
+    );
+}
+
+#[test]
+fn test_pairing_type_sections() {
+    let content = r#"[General]
+Name=Basilisk X HyperSpeed
+
+[LongTermKey]
+Key=00000000000000000000000000000000
+Authenticated=1
+EncSize=16
+EDiv=27023
+Rand=15138338010761522440
+
+[PeripheralLongTermKey]
+Key=414C87970DBAE282734D2BDCC1157C30
+Authenticated=0
+EncSize=16
+EDiv=27023
+Rand=15138338010761522440
+
+[SlaveLongTermKey]
+Key=414C87970DBAE282734D2BDCC1157C30
+Authenticated=0
+EncSize=16
+EDiv=27023
+Rand=15138338010761522440
+
+[DeviceID]
+Vendor=5426
+"#;
+
+    let secure = BtDeviceInfo {
+        ltk: "84417A06F13444B2780E0CC3CF1D353D".to_string(),
+        ediv: "0".to_string(),
+        erand: "0".to_string(),
+        enc_size: "16".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(secure.pairing_type(), PairingType::SecureConnections);
+    // Windows does not record MITM protection, so the file's is kept.
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(content, &secure, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("LongTermKey", "Authenticated"), Some("3"));
+    assert!(!keyfile.has_group("PeripheralLongTermKey"));
+    assert!(!keyfile.has_group("SlaveLongTermKey"));
+    assert_eq!(keyfile.get("DeviceID", "Vendor"), Some("5426"));
+
+    let legacy = BtDeviceInfo {
+        ltk: "D23FEDC5F5806AF8A37D41D81EE4DA5C".to_string(),
+        ediv: "34794".to_string(),
+        erand: "9659891662176722970".to_string(),
+        enc_size: "16".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(legacy.pairing_type(), PairingType::Legacy);
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(content, &legacy, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("LongTermKey", "Authenticated"), Some("1"));
+    assert_eq!(keyfile.get("LongTermKey", "EDiv"), Some("34794"));
+    assert_eq!(keyfile.get("LongTermKey", "Rand"), Some("9659891662176722970"));
+    assert!(!keyfile.has_group("PeripheralLongTermKey"));
+    assert!(!keyfile.has_group("SlaveLongTermKey"));
+
+    let unauthenticated = BtDeviceInfo { authenticated: Some(false), ..secure.clone() };
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(content, &unauthenticated, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("LongTermKey", "Authenticated"), Some("2"));
+    let created = keyfile::KeyFile::parse(&new_bt_info("Basilisk X HyperSpeed", &secure, PeripheralKeySection::Peripheral));
+    assert_eq!(created.get("LongTermKey", "Authenticated"), Some("2"));
+}
+
+#[test]
+fn test_peripheral_key_section_naming() {
+    let info = BtDeviceInfo {
+        ltk: "D23FEDC5F5806AF8A37D41D81EE4DA5C".to_string(),
+        ediv: "17209".to_string(),
+        erand: "189227263063048024".to_string(),
+        enc_size: "16".to_string(),
+        peripheral_ltk: "414C87970DBAE282734D2BDCC1157C30".to_string(),
+        peripheral_ediv: "27023".to_string(),
+        peripheral_erand: "15138338010761522440".to_string(),
+        ..Default::default()
+    };
+    let stale = "Key=00000000000000000000000000000000\nAuthenticated=0\nEncSize=16\nEDiv=1\nRand=1\n";
+
+    let old_only = update_bt_info(&format!("[SlaveLongTermKey]\n{}", stale), &info, PeripheralKeySection::Peripheral);
+    let keyfile = keyfile::KeyFile::parse(&old_only);
+    assert_eq!(keyfile.get("SlaveLongTermKey", "Key"), Some("414C87970DBAE282734D2BDCC1157C30"));
+    assert!(!keyfile.has_group("PeripheralLongTermKey"));
+
+    let new_only = update_bt_info(&format!("[PeripheralLongTermKey]\n{}", stale), &info, PeripheralKeySection::Slave);
+    let keyfile = keyfile::KeyFile::parse(&new_only);
+    assert_eq!(keyfile.get("PeripheralLongTermKey", "EDiv"), Some("27023"));
+    assert!(!keyfile.has_group("SlaveLongTermKey"));
+
+    let mixed = update_bt_info(&format!("[PeripheralLongTermKey]\n{}\n[SlaveLongTermKey]\n{}", stale, stale), &info, PeripheralKeySection::Slave);
+    let keyfile = keyfile::KeyFile::parse(&mixed);
+    for name in ["PeripheralLongTermKey", "SlaveLongTermKey"] {
+        assert_eq!(keyfile.get(name, "Key"), Some("414C87970DBAE282734D2BDCC1157C30"));
+        assert_eq!(keyfile.get(name, "Rand"), Some("15138338010761522440"));
+    }
+
+    let created = keyfile::KeyFile::parse(&update_bt_info("[General]\nName=test\n", &info, PeripheralKeySection::Slave));
+    assert!(created.has_group("SlaveLongTermKey"));
+    assert!(!created.has_group("PeripheralLongTermKey"));
+
+    assert_eq!(PeripheralKeySection::for_version("5.55\n"), Some(PeripheralKeySection::Slave));
+    assert_eq!(PeripheralKeySection::for_version("5.72"), Some(PeripheralKeySection::Peripheral));
+    assert_eq!(PeripheralKeySection::for_version("unknown"), None);
+}
+
+#[test]
+fn test_peripheral_key_sections_from_hive() -> Result<(), Box<dyn std::error::Error>> {
+    // Windows keeps no peripheral key, so the one in the file stays while the LTK is the same.
+    let hive = fs::read(Path::new(file!()).parent().unwrap().join("data/Windows/System32/config/SYSTEM"))?;
+    let (windows, _) = hive::read_hive_devices(&hive)?;
+    let mouse = device_named(&windows, "Basilisk X HyperSpeed").unwrap();
+    assert_eq!(mouse.pairing_type(), PairingType::Legacy);
+    assert!(mouse.peripheral_ltk.is_empty());
+
+    let section = |name: &str, key: &str| format!("[{}]\nKey={}\nAuthenticated=0\nEncSize=16\nEDiv=27023\nRand=15138338010761522440\n", name, key);
+    let sync = |ltk: &str, sections: &[String], peripheral_section| -> Result<keyfile::KeyFile, Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path().join(&mouse.mac);
+        fs::create_dir_all(&dir)?;
+        let content = format!("[General]\nName=Basilisk X HyperSpeed\n\n[LongTermKey]\nKey={}\nAuthenticated=0\nEncSize=16\nEDiv=1\nRand=1\n\n{}", ltk, sections.join("\n"));
+        fs::write(dir.join("info"), content)?;
+        let options = SyncOptions { peripheral_section, ..Default::default() };
+        process_bth_device(temp_dir.path().to_path_buf(), &windows, &options)?;
+        Ok(keyfile::KeyFile::parse(&fs::read_to_string(dir.join("info"))?))
+    };
+    let old = "414C87970DBAE282734D2BDCC1157C30";
+    let new = "0A1B2C3D4E5F60718293A4B5C6D7E8F9";
+
+    std::env::set_var("TESTING", "true");
+    let old_only = sync(&mouse.ltk, &[section("SlaveLongTermKey", old)], PeripheralKeySection::Peripheral)?;
+    assert_eq!(old_only.get("LongTermKey", "EDiv"), Some("34794"));
+    assert_eq!(old_only.get("SlaveLongTermKey", "Key"), Some(old));
+    assert!(!old_only.has_group("PeripheralLongTermKey"));
+
+    let new_only = sync(&mouse.ltk, &[section("PeripheralLongTermKey", new)], PeripheralKeySection::Slave)?;
+    assert_eq!(new_only.get("PeripheralLongTermKey", "Key"), Some(new));
+    assert!(!new_only.has_group("SlaveLongTermKey"));
+
+    // The sections disagree; the one the installed BlueZ maintains is copied into the other.
+    let mixed = [section("PeripheralLongTermKey", new), section("SlaveLongTermKey", old)];
+    let keyfile = sync(&mouse.ltk, &mixed, PeripheralKeySection::Slave)?;
+    assert_eq!(keyfile.get("PeripheralLongTermKey", "Key"), Some(old));
+    assert_eq!(keyfile.get("SlaveLongTermKey", "Key"), Some(old));
+    let keyfile = sync(&mouse.ltk, &mixed, PeripheralKeySection::Peripheral)?;
+    assert_eq!(keyfile.get("SlaveLongTermKey", "Key"), Some(new));
+    assert_eq!(keyfile.get("SlaveLongTermKey", "EDiv"), Some("27023"));
+
+    // Re-paired on Windows: the peripheral key belonged to the old pairing.
+    let keyfile = sync("00000000000000000000000000000000", &mixed, PeripheralKeySection::Peripheral)?;
+    assert_eq!(keyfile.get("LongTermKey", "Key"), Some(mouse.ltk.as_str()));
+    assert!(!keyfile.has_group("PeripheralLongTermKey"));
+    assert!(!keyfile.has_group("SlaveLongTermKey"));
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_adapter_identity() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let identity_path = temp_dir.path().join("identity");
+    fs::write(&identity_path, "[General]\nIdentityResolvingKey=0A1B2C3D4E5F60718293A4B5C6D7E8F9\n")?;
+
+    assert!(sync_adapter_identity(temp_dir.path(), "514EAE22DDAFF601CFFEE74B2E029AD5")?);
+    assert_eq!(fs::read_to_string(&identity_path)?, "[General]\nIdentityResolvingKey=514EAE22DDAFF601CFFEE74B2E029AD5\n");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("identity.bak"))?,
+        "[General]\nIdentityResolvingKey=0A1B2C3D4E5F60718293A4B5C6D7E8F9\n"
+    );
+
+    assert!(!sync_adapter_identity(temp_dir.path(), "514EAE22DDAFF601CFFEE74B2E029AD5")?);
+
+    Ok(())
+}
+
+#[test]
+fn test_dual_mode_device() {
+    let info = BtDeviceInfo {
+        link_key: "6C1B3F4E2A0D9C8B7A6F5E4D3C2B1A09".to_string(),
+        address_type: "public".to_string(),
+        ..windows_device("Headset", "64:B0:A6:B4:23:69", "84417A06F13444B2780E0CC3CF1D353D")
+    };
+
+    let content = "[General]\nName=Headset\nSupportedTechnologies=BR/EDR;\n\n[LinkKey]\nKey=00000000000000000000000000000000\nType=5\nPINLength=0\n";
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(content, &info, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("LinkKey", "Key"), Some("6C1B3F4E2A0D9C8B7A6F5E4D3C2B1A09"));
+    assert_eq!(keyfile.get("LinkKey", "Type"), Some("5"));
+    assert_eq!(keyfile.get("LongTermKey", "Key"), Some("84417A06F13444B2780E0CC3CF1D353D"));
+    assert_eq!(keyfile.get("General", "SupportedTechnologies"), Some("BR/EDR;LE;"));
+
+    let created = keyfile::KeyFile::parse(&new_bt_info("Headset", &info, PeripheralKeySection::Peripheral));
+    assert_eq!(created.get("General", "SupportedTechnologies"), Some("BR/EDR;LE;"));
+    assert_eq!(created.get("LinkKey", "Key"), Some("6C1B3F4E2A0D9C8B7A6F5E4D3C2B1A09"));
+    assert_eq!(created.get("LongTermKey", "Key"), Some("84417A06F13444B2780E0CC3CF1D353D"));
+
+    let classic = BtDeviceInfo { ltk: String::new(), ..info.clone() };
+    let created = keyfile::KeyFile::parse(&new_bt_info("Headset", &classic, PeripheralKeySection::Peripheral));
+    assert_eq!(created.get("General", "SupportedTechnologies"), Some("BR/EDR;"));
+    assert!(!created.has_group("LongTermKey"));
+
+    // Another BlueZ install records the key types, and they carry over as they are.
+    let source = "[LinkKey]\nKey=6C1B3F4E2A0D9C8B7A6F5E4D3C2B1A09\nType=8\nPINLength=0\n\n[LongTermKey]\nKey=84417A06F13444B2780E0CC3CF1D353D\nAuthenticated=3\nEncSize=16\nEDiv=0\nRand=0\n";
+    let authenticated = read_bt_info(source);
+    assert_eq!((authenticated.link_key_type.as_str(), authenticated.authenticated), ("8", Some(true)));
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(content, &authenticated, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("LinkKey", "Type"), Some("8"));
+    assert_eq!(keyfile.get("LongTermKey", "Authenticated"), Some("3"));
+    let created = keyfile::KeyFile::parse(&new_bt_info("Headset", &authenticated, PeripheralKeySection::Peripheral));
+    assert_eq!(created.get("LinkKey", "Type"), Some("8"));
+    assert_eq!(created.get("LongTermKey", "Authenticated"), Some("3"));
+    // Without them, the unauthenticated defaults are used.
+    assert_eq!(keyfile::KeyFile::parse(&new_bt_info("Headset", &info, PeripheralKeySection::Peripheral)).get("LinkKey", "Type"), Some("4"));
+
+    let reg = regfile::reg_device_entry("74:4C:A1:54:4B:8E", &info);
+    assert!(reg.contains("\\744ca1544b8e]\n\"64b0a6b42369\"=hex:6c,1b,3f,4e,2a,0d,9c,8b,7a,6f,5e,4d,3c,2b,1a,09\n"));
+    assert!(reg.contains("\\744ca1544b8e\\64b0a6b42369]\n\"LTK\"=hex:84,41"));
+}
+
+#[test]
+fn test_auto_sync_direction() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let windows_time = UNIX_EPOCH + Duration::from_secs(1733408012);
+
+    let devices = [
+        ("Mouse", "AA:AA:AA:AA:AA:01", "11111111111111111111111111111111", 1735689600),
+        ("Keyboard", "AA:AA:AA:AA:AA:02", "22222222222222222222222222222222", 1704067200),
+        ("Pad", "AA:AA:AA:AA:AA:03", "33333333333333333333333333333333", 1735689600),
+    ];
+    for (name, mac, ltk, mtime) in devices {
+        let dir = temp_dir.path().join(mac);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("info"), format!("[General]\nName={}\nAddressType=static\n\n[LongTermKey]\nKey={}\nAuthenticated=2\nEncSize=16\nEDiv=0\nRand=0\n", name, ltk))?;
+        fs::File::options().write(true).open(dir.join("info"))?.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+    // A dual-mode device whose link key changed on Windows while its LTK stayed the same.
+    let dir = temp_dir.path().join("AA:AA:AA:AA:AA:04");
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("info"), "[General]\nName=Headset\nAddressType=public\n\n[LinkKey]\nKey=66666666666666666666666666666666\nType=4\nPINLength=0\n\n[LongTermKey]\nKey=77777777777777777777777777777777\nAuthenticated=2\nEncSize=16\nEDiv=0\nRand=0\n")?;
+    fs::File::options().write(true).open(dir.join("info"))?.set_modified(UNIX_EPOCH + Duration::from_secs(1704067200))?;
+    fs::create_dir_all(temp_dir.path().join("AA:AA:AA:AA:AA:05"))?;
+
+    let bt_device_info = device_map([
+        windows_device("Mouse", "BB:BB:BB:BB:BB:01", "44444444444444444444444444444444"),
+        windows_device("Keyboard", "AA:AA:AA:AA:AA:02", "55555555555555555555555555555555"),
+        windows_device("Pad", "AA:AA:AA:AA:AA:03", "33333333333333333333333333333333"),
+        BtDeviceInfo {
+            link_key: "88888888888888888888888888888888".to_string(),
+            ..windows_device("Headset", "AA:AA:AA:AA:AA:04", "77777777777777777777777777777777")
+        },
+    ].map(|info| BtDeviceInfo { last_written: Some(windows_time), ..info }));
+
+    let decisions = decide_sync_direction(temp_dir.path(), &bt_device_info)?;
+    let sources: Vec<(&str, SyncSource)> = decisions.iter().map(|d| (d.name.as_str(), d.source)).collect();
+    assert_eq!(sources, [
+        ("Headset", SyncSource::Windows),
+        ("Keyboard", SyncSource::Windows),
+        ("Mouse", SyncSource::Linux),
+        ("Pad", SyncSource::Unchanged),
+    ]);
+    assert!(decisions[2].reason.contains("re-paired on Linux at 2025-01-01 00:00:00 UTC"));
+    assert!(decisions[2].reason.contains("2024-12-05 14:13:32 UTC"));
+    assert_eq!(decisions[2].linux.ltk, "11111111111111111111111111111111");
+    assert_eq!(decisions[2].linux.adapter, "74:4C:A1:54:4B:8E");
+
+    let reg = regfile::windows_update_reg(&decisions[2..3]);
+    assert!(reg.contains("[-HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Services\\BTHPORT\\Parameters\\Keys\\744ca1544b8e\\bbbbbbbbbb01]"));
+    assert!(reg.contains("\\744ca1544b8e\\aaaaaaaaaa01]\n\"LTK\"=hex:11,11"));
+
+    Ok(())
+}
+
+#[test]
+fn test_pairing_status() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let adapter = temp_dir.path().join("74:4C:A1:54:4B:8E");
+
+    let linux = [
+        ("Same", "AA:AA:AA:AA:AA:01", "11111111111111111111111111111111"),
+        ("Rekeyed", "AA:AA:AA:AA:AA:02", "22222222222222222222222222222222"),
+        ("Moved", "AA:AA:AA:AA:AA:03", "33333333333333333333333333333333"),
+        ("Linux Only", "AA:AA:AA:AA:AA:04", "44444444444444444444444444444444"),
+    ];
+    for (name, mac, ltk) in linux {
+        fs::create_dir_all(adapter.join(mac))?;
+        fs::write(adapter.join(mac).join("info"), format!("[General]\nName={}\n\n[LongTermKey]\nKey={}\n", name, ltk))?;
+    }
+    // A device directory without an info file is left out with a warning.
+    fs::create_dir_all(adapter.join("AA:AA:AA:AA:AA:07"))?;
+
+    let bt_device_info = device_map([
+        windows_device("Same", "AA:AA:AA:AA:AA:01", "11111111111111111111111111111111"),
+        windows_device("Rekeyed", "AA:AA:AA:AA:AA:02", "55555555555555555555555555555555"),
+        windows_device("Moved", "BB:BB:BB:BB:BB:03", "33333333333333333333333333333333"),
+        windows_device("Windows Only", "BB:BB:BB:BB:BB:06", "66666666666666666666666666666666"),
+    ]);
+
+    let statuses = pairing_status(temp_dir.path(), &bt_device_info, &SyncOptions::default())?;
+    let states: Vec<(&str, &str, DeviceState)> = statuses.iter().map(|s| (s.name.as_str(), s.mac.as_str(), s.state)).collect();
+    assert_eq!(states, [
+        ("Linux Only", "AA:AA:AA:AA:AA:04", DeviceState::LinuxOnly),
+        ("Moved", "AA:AA:AA:AA:AA:03", DeviceState::AddressDiffers),
+        ("Rekeyed", "AA:AA:AA:AA:AA:02", DeviceState::KeyDiffers),
+        ("Same", "AA:AA:AA:AA:AA:01", DeviceState::InSync),
+        ("Windows Only", "BB:BB:BB:BB:BB:06", DeviceState::WindowsOnly),
+    ]);
+
+    // Nothing is written while comparing.
+    assert_eq!(fs::read_dir(&adapter)?.count(), 5);
+
+    Ok(())
+}
+
+#[test]
+fn test_doctor_diagnosis() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = tempdir()?;
+    let adapter = temp_dir.path().join("74:4C:A1:54:4B:8E");
+
+    let linux = [
+        ("AA:AA:AA:AA:AA:01", "Name=Mouse\n"),
+        ("AA:AA:AA:AA:AA:02", "Name=Headset\n"),
+        ("AA:AA:AA:AA:AA:03", "Name=My Pad\n"),
+        ("AA:AA:AA:AA:AA:04", "Class=0x240404\n"),
+        ("AA:AA:AA:AA:AA:05", "Name=Speaker\n"),
+    ];
+    for (mac, general) in linux {
+        fs::create_dir_all(adapter.join(mac))?;
+        fs::write(adapter.join(mac).join("info"), format!("[General]\n{}", general))?;
+    }
+
+    let ltk = "11111111111111111111111111111111";
+    let bt_device_info = device_map([
+        windows_device("Mouse", "AA:AA:AA:AA:AA:01", ltk),
+        windows_device("Gamepad", "AA:AA:AA:AA:AA:03", ltk),
+        windows_device("Keyboard", "AA:AA:AA:AA:AA:06", ltk),
+    ]);
+    let skipped = [hive::SkippedKey {
+        adapter: "74:4C:A1:54:4B:8E".to_string(),
+        mac: "AA:AA:AA:AA:AA:02".to_string(),
+        reason: "no Name".to_string(),
+        hint: "connect it on Windows".to_string(),
+    }];
+
+    let diagnoses = doctor::diagnose(temp_dir.path(), &bt_device_info, &skipped, &SyncOptions::default())?;
+    let results: Vec<(&str, bool, &str)> = diagnoses.iter().map(|d| (d.name.as_str(), d.ok, d.reason.as_str())).collect();
+    assert_eq!(results, [
+        ("AA:AA:AA:AA:AA:04", false, "info has no Name= under [General]"),
+        ("Headset", false, "the Windows key for this address was skipped: no Name"),
+        ("Keyboard", false, "no Linux pairing named Keyboard"),
+        ("My Pad", false, "named My Pad on Linux but Gamepad on Windows"),
+        ("Speaker", false, "no Windows pairing named Speaker"),
+        ("Mouse", true, "matched, will be updated"),
+    ]);
+    assert_eq!(diagnoses[1].hint, "connect it on Windows");
+    assert_eq!(diagnoses[3].hint, "set Name=Gamepad under [General] in its info file");
+
+    Ok(())
+}
+
+#[test]
+fn test_inspect_hive() -> Result<()> {
+    let buf = fs::read(Path::new(file!()).parent().unwrap().join("data/Windows/System32/config/SYSTEM"))?;
+    let parameters = inspect::inspect_hive(&buf)?;
+
+    let subkey = |key: &inspect::RegKey, name: &str| key.subkeys.iter().find(|k| k.name == name).cloned().unwrap();
+    let decoded = |key: &inspect::RegKey, name: &str| key.values.iter().find(|v| v.name == name).and_then(|v| v.decoded.clone());
+
+    let xbox = subkey(&subkey(&parameters, "Devices"), "ac8ebd24ac52");
+    assert_eq!(decoded(&xbox, "Name").as_deref(), Some("Xbox Wireless Controller"));
+    assert_eq!(decoded(&xbox, "LastConnected").as_deref(), Some("2024-11-10 19:03:52 UTC"));
+    assert_eq!(decoded(&xbox, "VID"), None);
+
+    let keys = subkey(&subkey(&parameters, "Keys"), "744ca1544b8e");
+    assert_eq!(decoded(&keys, "CentralIRK").as_deref(), Some("514EAE22DDAFF601CFFEE74B2E029AD5"));
+    let basilisk = subkey(&keys, "fc51caac5711");
+    assert_eq!(decoded(&basilisk, "LTK").as_deref(), Some("D23FEDC5F5806AF8A37D41D81EE4DA5C"));
+    assert_eq!(decoded(&basilisk, "EDIV").as_deref(), Some("34794"));
+    assert_eq!(decoded(&basilisk, "ERand").as_deref(), Some("9659891662176722970"));
+    assert_eq!(decoded(&basilisk, "IRK").as_deref(), Some("8EC94951919F694C8DBFD5E0BEA21536"));
+
+    fn all_written(key: &inspect::RegKey) -> bool {
+        key.last_written.is_some() && key.subkeys.iter().all(all_written)
+    }
+    assert!(all_written(&parameters));
+
+    // A key node cell: 4 byte size, "nk", flags, the FILETIME it was last written, ..., the name
+    // length at 72 and the name at 76 from the signature. Rewriting the time shows up everywhere.
+    let mut rewritten = buf.clone();
+    let name = b"fc51caac5711";
+    let cells: Vec<usize> = (0..buf.len() - 88)
+        .filter(|&i| buf[i..].starts_with(b"nk") && buf[i + 72..i + 74] == [name.len() as u8, 0] && buf[i + 76..].starts_with(name))
+        .collect();
+    assert!(!cells.is_empty());
+    let filetime = (1_577_836_800u64 + 11_644_473_600) * 10_000_000;
+    for cell in cells {
+        rewritten[cell + 4..cell + 12].copy_from_slice(&filetime.to_le_bytes());
+    }
+    let key = subkey(&subkey(&subkey(&inspect::inspect_hive(&rewritten)?, "Keys"), "744ca1544b8e"), "fc51caac5711");
+    assert_eq!(key.last_written, Some(UNIX_EPOCH + Duration::from_secs(1_577_836_800)));
+    let (windows, _) = hive::read_hive_devices(&rewritten)?;
+    assert_eq!(device_named(&windows, "Basilisk X HyperSpeed").unwrap().last_written, Some(UNIX_EPOCH + Duration::from_secs(1_577_836_800)));
+
+    let tree = inspect::format_tree(&parameters);
+    assert!(tree.starts_with("Parameters [2024-12-05 14:13:32 UTC]\n├── Devices"));
+    assert!(tree.contains("│   │   ├── Name = Xbox Wireless Controller\n"));
+    assert!(tree.contains("├── LEAppearance = hex:C4030000\n"));
+
+    let json: serde_json::Value = serde_json::from_str(&inspect::format_json(&parameters)?)?;
+    assert_eq!(json["name"], "Parameters");
+    assert_eq!(json["last_written"], "2024-12-05 14:13:32 UTC");
+    let json_subkey = |key: &serde_json::Value, name: &str| key["subkeys"].as_array().unwrap().iter().find(|k| k["name"] == name).cloned().unwrap();
+    let xbox = json_subkey(&json_subkey(&json, "Devices"), "ac8ebd24ac52");
+    let name = xbox["values"].as_array().unwrap().iter().find(|v| v["name"] == "Name").unwrap();
+    assert_eq!(name, &serde_json::json!({
+        "name": "Name",
+        "type": "REG_BINARY",
+        "hex": "58626F7820576972656C65737320436F6E74726F6C6C657200",
+        "value": "Xbox Wireless Controller",
+    }));
+    let vid = xbox["values"].as_array().unwrap().iter().find(|v| v["name"] == "VID").unwrap();
+    assert!(vid.get("value").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_big_data_values() -> Result<()> {
+    // The fixture hive with the Xbox controller's Name grown to 20000 bytes, which
+    // Windows stores as big data: a "db" cell listing 16344 byte segments.
+    let buf = fs::read(Path::new(file!()).parent().unwrap().join("data/big_data/SYSTEM"))?;
+    let name = "Xbox Wireless Controller with a name long enough for big data";
+
+    let (windows, skipped) = hive::read_hive_devices(&buf)?;
+    assert!(skipped.is_empty());
+    assert_eq!(windows[&("74:4C:A1:54:4B:8E".to_string(), "AC:8E:BD:24:AC:52".to_string())].name, name);
+
+    let parameters = inspect::inspect_hive(&buf)?;
+    let devices = parameters.subkeys.iter().find(|k| k.name == "Devices").unwrap();
+    let xbox = devices.subkeys.iter().find(|k| k.name == "ac8ebd24ac52").unwrap();
+    let value = xbox.values.iter().find(|v| v.name == "Name").unwrap();
+    assert_eq!(value.data.len(), 20000);
+    assert_eq!(value.decoded.as_deref(), Some(name));
+
+    Ok(())
+}
+
+#[test]
+fn test_malformed_names() {
+    assert_eq!(hive::decode_name(b"Xbox Wireless Controller\0\0", "ac8ebd24ac52"), "Xbox Wireless Controller");
+    // Windows-1252 "Café" is not UTF-8.
+    assert_eq!(hive::decode_name(b"Caf\xe9 Speaker\0", "ac8ebd24ac52"), "Caf\u{fffd} Speaker");
+    assert_eq!(hive::decode_name(b"", "ac8ebd24ac52"), "");
+
+    assert_eq!(fmt_mac("ac8ebd24ac5"), "AC:8E:BD:24:AC:5");
+    assert_eq!(fmt_mac("é1"), "É1");
+}
+
+/// Renames the value `name` whose data starts with `data`, in place.
+fn rename_hive_value(hive: &mut [u8], name: &[u8], data: &[u8], new_name: &[u8]) {
+    let mut pattern = b"vk".to_vec();
+    pattern.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    let position = (0..hive.len() - 20).find(|&i| {
+        if !hive[i..].starts_with(&pattern) || !hive[i + 20..].starts_with(name) {
+            return false;
+        }
+        let offset = u32::from_le_bytes(hive[i + 8..i + 12].try_into().unwrap()) as usize;
+        hive.get(4096 + offset + 4..).is_some_and(|cell| cell.starts_with(data))
+    }).unwrap();
+    hive[position + 20..position + 20 + new_name.len()].copy_from_slice(new_name);
+}
+
+/// Replaces the data of every DWORD value `name` holding `data` in its value cell.
+fn set_hive_dword(hive: &mut [u8], name: &[u8], data: u32, new_data: u32) {
+    let mut pattern = b"vk".to_vec();
+    pattern.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    pattern.extend_from_slice(&0x8000_0004u32.to_le_bytes());
+    pattern.extend_from_slice(&data.to_le_bytes());
+    let positions: Vec<usize> = (0..hive.len() - 20).filter(|&i| hive[i..].starts_with(&pattern) && hive[i + 20..].starts_with(name)).collect();
+    assert!(!positions.is_empty());
+    for position in positions {
+        hive[position + 8..position + 12].copy_from_slice(&new_data.to_le_bytes());
+    }
+}
+
+#[test]
+fn test_device_name_fallback() -> Result<()> {
+    let fixture = Path::new(file!()).parent().unwrap().join("data/Windows/System32/config/SYSTEM");
+    let mut hive = fs::read(fixture)?;
+    // The keyboard loses its Name and falls back to LEName, the mouse loses both.
+    rename_hive_value(&mut hive, b"Name", b"BT+2.4G KB", b"Xame");
+    rename_hive_value(&mut hive, b"Name", b"Basilisk", b"Xame");
+    rename_hive_value(&mut hive, b"LEName", b"Basilisk", b"XEName");
+
+    let temp_dir = tempdir()?;
+    let config = temp_dir.path().join("Windows/System32/config");
+    fs::create_dir_all(&config)?;
+    fs::write(config.join("SYSTEM"), hive)?;
+
+    let result = parse_reg("/dev/test", temp_dir.path().to_str().unwrap())?;
+    let mut names: Vec<&str> = result.values().map(|info| info.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["BT+2.4G KB", "FC:51:CA:AC:57:11", "Xbox Wireless Controller"]);
+    assert_eq!(device_named(&result, "FC:51:CA:AC:57:11").unwrap().ltk, "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+
+    // An unnamed Windows key still updates the Linux pairing with its address.
+    let linux = tempdir()?;
+    fs::create_dir_all(linux.path().join("FC:51:CA:AC:57:11"))?;
+    fs::write(linux.path().join("FC:51:CA:AC:57:11/info"), "[General]\nName=Basilisk X HyperSpeed\n\n[LongTermKey]\nKey=00000000000000000000000000000000\n")?;
+    process_bth_device(linux.path().to_path_buf(), &result, &SyncOptions::default()).unwrap();
+    assert_eq!(get_ltk(&fs::read_to_string(linux.path().join("FC:51:CA:AC:57:11/info"))?), "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+
+    // Imported without a name, so bluetoothd can fill it in.
+    let created = keyfile::KeyFile::parse(&new_bt_info("FC:51:CA:AC:57:11", device_named(&result, "FC:51:CA:AC:57:11").unwrap(), PeripheralKeySection::Peripheral));
+    assert_eq!(created.get("General", "Name"), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_device_metadata() -> Result<()> {
+    let info = BtDeviceInfo {
+        mac: "FC:51:CA:AC:57:11".to_string(),
+        ltk: "D23FEDC5F5806AF8A37D41D81EE4DA5C".to_string(),
+        class: "0x002540".to_string(),
+        appearance: "0x03c2".to_string(),
+        device_id_source: "2".to_string(),
+        vendor: "5426".to_string(),
+        product: "130".to_string(),
+        version: "1".to_string(),
+        ..Default::default()
+    };
+
+    let stale = "[General]\nName=Basilisk X HyperSpeed\nAppearance=0x0000\n\n[DeviceID]\nSource=1\nVendor=0\nProduct=0\nVersion=0\n";
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(stale, &info, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("General", "Appearance"), Some("0x03c2"));
+    assert_eq!(keyfile.get("General", "Class"), Some("0x002540"));
+    assert_eq!(keyfile.keys("DeviceID"), ["Source", "Vendor", "Product", "Version"]);
+    assert_eq!(keyfile.get("DeviceID", "Source"), Some("2"));
+    assert_eq!(keyfile.get("DeviceID", "Vendor"), Some("5426"));
+    assert_eq!(keyfile.get("DeviceID", "Product"), Some("130"));
+
+    // Nothing known on Windows leaves the Linux values alone.
+    let bare = BtDeviceInfo { ltk: info.ltk.clone(), ..Default::default() };
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(stale, &bare, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("General", "Appearance"), Some("0x0000"));
+    assert_eq!(keyfile.get("DeviceID", "Vendor"), Some("0"));
+
+    let created = keyfile::KeyFile::parse(&new_bt_info("Basilisk X HyperSpeed", &info, PeripheralKeySection::Peripheral));
+    assert_eq!(created.get("General", "Appearance"), Some("0x03c2"));
+    assert_eq!(created.get("DeviceID", "Version"), Some("1"));
+
+    // Windows writes 0 for an appearance it never learned, which is no value at all.
+    let mut hive = fs::read(Path::new(file!()).parent().unwrap().join("data/Windows/System32/config/SYSTEM"))?;
+    set_hive_dword(&mut hive, b"LEAppearance", 0x03c1, 0);
+    let (windows, _) = hive::read_hive_devices(&hive)?;
+    let keyboard = device_named(&windows, "BT+2.4G KB").unwrap();
+    assert_eq!(keyboard.appearance, "");
+    let keyfile = keyfile::KeyFile::parse(&update_bt_info(stale, keyboard, PeripheralKeySection::Peripheral));
+    assert_eq!(keyfile.get("General", "Appearance"), Some("0x0000"));
+    assert_eq!(device_named(&windows, "Xbox Wireless Controller").unwrap().appearance, "0x03c4");
+
+    Ok(())
+}
+
+#[test]
+fn test_aggregate_installs() -> Result<()> {
+    use partitions::{merge_installs, SourcePolicy};
+
+    let path = Path::new(file!()).parent().unwrap().join("data");
+    let first = parse_reg("/dev/sda3", path.to_str().unwrap())?;
+    let mut second = parse_reg("/dev/nvme0n1p3", path.to_str().unwrap())?;
+
+    // The second install re-paired the keyboard later and knows one extra device.
+    let keyboard = second.values_mut().find(|info| info.name == "BT+2.4G KB").unwrap();
+    keyboard.ltk = "00112233445566778899AABBCCDDEEFF".to_string();
+    keyboard.last_written = keyboard.last_written.map(|time| time + Duration::from_secs(60));
+    second.extend(device_map([BtDeviceInfo {
+        name: "Headset".to_string(),
+        adapter: "74:4C:A1:54:4B:8E".to_string(),
+        mac: "00:1B:66:00:00:01".to_string(),
+        link_key: "0123456789ABCDEF0123456789ABCDEF".to_string(),
+        source: "/dev/nvme0n1p3".to_string(),
+        ..Default::default()
+    }]));
+    // It also paired the keyboard with a second adapter, which is a pairing of its own.
+    let other_adapter = BtDeviceInfo {
+        adapter: "00:1A:7D:DA:71:13".to_string(),
+        source: "/dev/nvme0n1p3".to_string(),
+        ..device_named(&first, "BT+2.4G KB").unwrap().clone()
+    };
+    second.insert(other_adapter.key(), other_adapter.clone());
+    let installs = vec![first, second];
+    let keyboard_key = ("74:4C:A1:54:4B:8E".to_string(), "E0:10:5F:A9:F6:59".to_string());
+
+    let (merged, conflicts) = merge_installs(installs.clone(), &SourcePolicy::First);
+    assert_eq!(merged.len(), 5);
+    assert_eq!(merged[&other_adapter.key()], other_adapter);
+    assert_eq!(merged[&keyboard_key].source, "/dev/sda3");
+    assert_eq!(device_named(&merged, "Headset").unwrap().source, "/dev/nvme0n1p3");
+    // Identical pairings on both installs are not conflicts.
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].name, "BT+2.4G KB");
+    assert_eq!(conflicts[0].candidates.len(), 2);
+    assert_eq!(conflicts[0].chosen, 0);
+
+    let (merged, conflicts) = merge_installs(installs.clone(), &SourcePolicy::Newest);
+    assert_eq!(merged[&keyboard_key].ltk, "00112233445566778899AABBCCDDEEFF");
+    assert_eq!(conflicts[0].chosen, 1);
+    // Equal timestamps keep the first install.
+    assert_eq!(device_named(&merged, "Xbox Wireless Controller").unwrap().source, "/dev/sda3");
+
+    let (merged, _) = merge_installs(installs.clone(), &SourcePolicy::Partition("/dev/nvme0n1p3".to_string()));
+    assert_eq!(merged[&keyboard_key].source, "/dev/nvme0n1p3");
+    assert_eq!(device_named(&merged, "Basilisk X HyperSpeed").unwrap().source, "/dev/nvme0n1p3");
+
+    let args: Vec<String> = ["status", "--aggregate", "newest"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(cli::parse_args(&args)?, cli::Command::Status(SyncOptions { aggregate: Some(SourcePolicy::Newest), ..Default::default() }));
+    let args: Vec<String> = ["--aggregate", "latest"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_linux_source() -> Result<(), Box<dyn std::error::Error>> {
+    use bluez::{read_bluez_tree, read_linux_devices, LinuxSource};
+
+    let temp_dir = tempdir()?;
+    let other = temp_dir.path().join("other");
+    let running = temp_dir.path().join("running");
+    let adapter = "74:4C:A1:54:4B:8E";
+
+    fs::create_dir_all(other.join(adapter).join("FC:51:CA:AC:57:11"))?;
+    fs::create_dir_all(other.join(adapter).join("00:1B:66:00:00:01"))?;
+    fs::create_dir_all(other.join(adapter).join("11:22:33:44:55:66"))?;
+    fs::create_dir_all(running.join(adapter).join("FC:51:CA:AC:57:11"))?;
+    fs::write(other.join(adapter).join("identity"), "[General]\nIdentityResolvingKey=514eae22ddaff601cffee74b2e029ad5\n")?;
+    fs::write(other.join(adapter).join("FC:51:CA:AC:57:11").join("info"),
+        "[General]\nName=Basilisk X HyperSpeed\nAddressType=static\n\n[LongTermKey]\nKey=D23FEDC5F5806AF8A37D41D81EE4DA5C\nEncSize=16\nEDiv=34794\nRand=9659891662176722970\n")?;
+    // No name, and a device that never finished pairing.
+    fs::write(other.join(adapter).join("00:1B:66:00:00:01").join("info"),
+        "[General]\nSupportedTechnologies=BR/EDR;\n\n[LinkKey]\nKey=0123456789ABCDEF0123456789ABCDEF\nType=4\nPINLength=0\n")?;
+    fs::write(other.join(adapter).join("11:22:33:44:55:66").join("info"), "[General]\nName=Never Paired\n")?;
+    // The same mouse also paired with a second adapter.
+    fs::create_dir_all(other.join("00:1A:7D:DA:71:13").join("FC:51:CA:AC:57:11"))?;
+    fs::write(other.join("00:1A:7D:DA:71:13").join("FC:51:CA:AC:57:11").join("info"),
+        "[General]\nName=Basilisk X HyperSpeed\nAddressType=static\n\n[LongTermKey]\nKey=11111111111111111111111111111111\n")?;
+    fs::write(running.join(adapter).join("FC:51:CA:AC:57:11").join("info"),
+        "[General]\nName=Basilisk X HyperSpeed\nAddressType=static\n\n[LongTermKey]\nKey=00000000000000000000000000000000\nEncSize=16\nEDiv=1\nRand=1\n")?;
+
+    let bt_device_info = read_bluez_tree(&other, "other")?;
+    assert_eq!(bt_device_info.len(), 3);
+    assert_eq!(bt_device_info[&("00:1A:7D:DA:71:13".to_string(), "FC:51:CA:AC:57:11".to_string())].ltk, "11111111111111111111111111111111");
+    let mouse = &bt_device_info[&(adapter.to_string(), "FC:51:CA:AC:57:11".to_string())];
+    assert_eq!(mouse.name, "Basilisk X HyperSpeed");
+    assert_eq!(mouse.source, "other");
+    assert_eq!(mouse.local_irk, "514EAE22DDAFF601CFFEE74B2E029AD5");
+    assert!(mouse.last_written.is_some());
+    assert_eq!(device_named(&bt_device_info, "00:1B:66:00:00:01").unwrap().link_key, "0123456789ABCDEF0123456789ABCDEF");
+
+    std::env::set_var("TESTING", "true");
+    // Only the pairing on the matching adapter is applied.
+    assert!(apply_to_bluez(&running, &bt_device_info, &SyncOptions::default())?);
+    let content = fs::read_to_string(running.join(adapter).join("FC:51:CA:AC:57:11").join("info"))?;
+    assert_eq!(get_ltk(&content), "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+    assert!(content.contains("EDiv=34794"));
+    // A device that cannot be read fails the sync instead of reporting nothing to do.
+    fs::create_dir_all(running.join(adapter).join("00:1B:66:00:00:02").join("info"))?;
+    let error = apply_to_bluez(&running, &bt_device_info, &SyncOptions::default()).unwrap_err();
+    assert!(error.to_string().starts_with("Failed to update devices in"), "{}", error);
+    fs::remove_dir_all(running.join(adapter).join("00:1B:66:00:00:02"))?;
+
+    assert!(read_linux_devices(&LinuxSource::Directory(running.clone()), &running).is_err());
+
+    let args: Vec<String> = ["--from-linux", "/dev/sdb2"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(cli::parse_args(&args)?, cli::Command::Sync(SyncOptions { from_linux: Some(LinuxSource::Partition("/dev/sdb2".to_string())), ..Default::default() }));
+    let args: Vec<String> = ["--from-linux", "auto", "--direction", "auto"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_pairing_stores() -> Result<(), Box<dyn std::error::Error>> {
+    use bundle::JsonFile;
+    use hive::HiveFile;
+    use regfile::RegFile;
+    use store::{sync_pairings, PairingSink, PairingSource, StoreSpec};
+
+    let temp_dir = tempdir()?;
+    let fixture = Path::new(file!()).parent().unwrap().join("data/Windows/System32/config/SYSTEM");
+    let hive_path = temp_dir.path().join("SYSTEM");
+    fs::copy(&fixture, &hive_path)?;
+    let options = SyncOptions::default();
+
+    let windows = HiveFile { path: hive_path.clone() }.read()?;
+    assert_eq!(windows.len(), 3);
+    assert_eq!(device_named(&windows, "BT+2.4G KB").unwrap().source, hive_path.display().to_string());
+
+    // JSON keeps every field but where the pairing came from.
+    let json = JsonFile { path: temp_dir.path().join("pairings.json") };
+    json.write(&windows, &options)?;
+    let from_json = json.read()?;
+    for (key, info) in &windows {
+        assert_eq!(BtDeviceInfo { source: String::new(), ..from_json[key].clone() }, BtDeviceInfo { source: String::new(), ..info.clone() });
+    }
+    assert!(bundle::from_json(r#"{"version": 99, "devices": []}"#).is_err());
+
+    // A .reg file carries the keys, and names through its comments.
+    let reg = RegFile { path: temp_dir.path().join("pairings.reg") };
+    reg.write(&windows, &options)?;
+    let from_reg = reg.read()?;
+    assert_eq!(from_reg.len(), 3);
+    for (key, info) in &windows {
+        let read = &from_reg[key];
+        assert_eq!((&read.adapter, &read.mac, &read.ltk, &read.erand, &read.ediv, &read.irk, &read.address_type, &read.enc_size),
+            (&info.adapter, &info.mac, &info.ltk, &info.erand, &info.ediv, &info.irk, &info.address_type, &info.enc_size));
+    }
+
+    // regedit exports wrap long lines and name devices under Devices.
+    let export = "Windows Registry Editor Version 5.00\r\n\r\n\
+        [HKEY_LOCAL_MACHINE\\SYSTEM\\ControlSet001\\Services\\BTHPORT\\Parameters\\Devices\\001b66000001]\r\n\
+        \"Name\"=hex:48,65,61,64,73,65,74,00\r\n\r\n\
+        [HKEY_LOCAL_MACHINE\\SYSTEM\\ControlSet001\\Services\\BTHPORT\\Parameters\\Keys\\744ca1544b8e]\r\n\
+        \"CentralIRK\"=hex:d5,9a,02,2e,4b,e7,fe,cf,01,f6,af,dd,22,ae,4e,51\r\n\
+        \"001b66000001\"=hex:01,23,45,67,89,ab,cd,ef,01,23,45,67,\\\r\n  89,ab,cd,ef\r\n";
+    let exported = regfile::parse_reg_file(&regfile::decode_reg(&regfile::encode_reg(export)));
+    let headset = device_named(&exported, "Headset").unwrap();
+    assert_eq!(headset.mac, "00:1B:66:00:00:01");
+    assert_eq!(headset.link_key, "0123456789ABCDEF0123456789ABCDEF");
+    assert_eq!(headset.local_irk, "514EAE22DDAFF601CFFEE74B2E029AD5");
+
+    // The hive is patched in place; devices it does not hold are reported.
+    let mut changed = windows.clone();
+    let keyboard = changed.values_mut().find(|info| info.name == "BT+2.4G KB").unwrap();
+    keyboard.ltk = "00112233445566778899AABBCCDDEEFF".to_string();
+    keyboard.erand = "42".to_string();
+    keyboard.ediv = "7".to_string();
+    changed.insert(headset.key(), headset.clone());
+    let mut buf = fs::read(&hive_path)?;
+    let patch = hive::patch_hive(&mut buf, &changed)?;
+    assert_eq!(patch.updated, vec!["BT+2.4G KB".to_string()]);
+    assert_eq!(patch.unsupported.len(), 1);
+    assert_eq!(patch.unsupported[0].0, "Headset");
+
+    let mut bad_number = changed.clone();
+    bad_number.values_mut().find(|info| info.name == "BT+2.4G KB").unwrap().erand = "0x2a".to_string();
+    let patch = hive::patch_hive(&mut fs::read(&hive_path)?, &bad_number)?;
+    assert!(patch.updated.is_empty());
+    assert!(patch.unsupported.iter().any(|(name, reason)| name == "BT+2.4G KB" && reason.contains("ERand")));
+
+    // A hive Windows has not finished writing is left alone: mismatched sequence numbers in the
+    // base block, or a transaction log with entries from the hive's sequence number onwards.
+    let mut dirty = fs::read(&hive_path)?;
+    dirty[4..8].copy_from_slice(&163u32.to_le_bytes());
+    assert!(hive::patch_hive(&mut dirty, &changed).is_err());
+    let log = |sequence: u32| [b"regf".to_vec(), vec![0; 508], b"HvLE".to_vec(), 512u32.to_le_bytes().to_vec(), vec![0; 4], sequence.to_le_bytes().to_vec(), vec![0; 496]].concat();
+    fs::write(temp_dir.path().join("SYSTEM.LOG1"), log(162))?;
+    assert!(HiveFile { path: hive_path.clone() }.write(&changed, &options).is_err());
+    assert_eq!(fs::read(&hive_path)?, fs::read(&fixture)?);
+    fs::write(temp_dir.path().join("SYSTEM.LOG1"), log(161))?;
+    fs::write(temp_dir.path().join("SYSTEM.LOG2"), vec![0; 4096])?;
+
+    assert!(HiveFile { path: hive_path.clone() }.write(&changed, &options)?);
+    assert!(temp_dir.path().join("SYSTEM.bak").exists());
+    let patched = HiveFile { path: hive_path.clone() }.read()?;
+    assert_eq!(device_named(&patched, "BT+2.4G KB").unwrap().ltk, "00112233445566778899AABBCCDDEEFF");
+    assert_eq!(device_named(&patched, "BT+2.4G KB").unwrap().erand, "42");
+    assert_eq!(device_named(&patched, "BT+2.4G KB").unwrap().ediv, "7");
+    assert_eq!(device_named(&patched, "Xbox Wireless Controller").unwrap(), device_named(&windows, "Xbox Wireless Controller").unwrap());
+
+    // Any source into any sink: the JSON file into a BlueZ tree.
+    let bluez = temp_dir.path().join("bluetooth");
+    let device = bluez.join("74:4C:A1:54:4B:8E").join("FC:51:CA:AC:57:11");
+    fs::create_dir_all(&device)?;
+    fs::write(device.join("info"), "[General]\nName=Basilisk X HyperSpeed\n\n[LongTermKey]\nKey=00000000000000000000000000000000\n")?;
+    std::env::set_var("TESTING", "true");
+    let sink = bluez::BluezTree { path: bluez.clone() };
+    assert!(sync_pairings(&json, &sink, &options)?);
+    assert_eq!(get_ltk(&fs::read_to_string(device.join("info"))?), "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+
+    assert_eq!(StoreSpec::parse("reg:out.reg")?, StoreSpec::Reg("out.reg".into()));
+    assert!(StoreSpec::parse("mbox:out.mbox").is_err());
+    let args: Vec<String> = ["--source", "json:in.json", "--sink", "hive:SYSTEM"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(cli::parse_args(&args)?, cli::Command::Sync(SyncOptions {
+        source: Some(StoreSpec::Json("in.json".into())),
+        sink: Some(StoreSpec::Hive("SYSTEM".into())),
+        ..Default::default()
+    }));
+    let args: Vec<String> = ["--direction", "auto", "--sink", "reg:out.reg"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_bundle() -> Result<(), Box<dyn std::error::Error>> {
+    use bundle::{seal, unseal, EncryptedBundle};
+    use store::{sync_pairings, PairingSink, PairingSource, StoreSpec};
+
+    let temp_dir = tempdir()?;
+    let path = Path::new(file!()).parent().unwrap().join("data");
+    let windows = parse_reg("/dev/test", path.to_str().unwrap())?;
+    let options = SyncOptions::default();
+
+    let bundle = EncryptedBundle { path: temp_dir.path().join("out.btk"), passphrase: Some("correct horse".to_string()) };
+    bundle.write(&windows, &options)?;
+    let sealed = fs::read(&bundle.path)?;
+    assert!(sealed.starts_with(b"BTK\x01"));
+    // Nothing readable is left in the file.
+    assert!(!String::from_utf8_lossy(&sealed).contains("039D9DE0952391208B4F755257E6425B"));
+
+    let restored = bundle.read()?;
+    for (key, info) in &windows {
+        assert_eq!(BtDeviceInfo { source: String::new(), ..restored[key].clone() }, BtDeviceInfo { source: String::new(), ..info.clone() });
+    }
+
+    let wrong = EncryptedBundle { path: bundle.path.clone(), passphrase: Some("wrong horse".to_string()) };
+    assert!(wrong.read().unwrap_err().to_string().contains("Wrong passphrase"));
+    let mut tampered = seal("{}", "pass")?;
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(unseal(&tampered, "pass").is_err());
+    let mut future = seal("{}", "pass")?;
+    future[3] = 2;
+    assert!(unseal(&future, "pass").unwrap_err().to_string().contains("version 2"));
+    // The Argon2 algorithm and costs are in the authenticated header: Argon2id, 19 MiB, two passes, one lane.
+    let sealed = seal("{}", "pass")?;
+    assert_eq!(sealed[4..17], [[2].as_slice(), &19456u32.to_le_bytes(), &2u32.to_le_bytes(), &1u32.to_le_bytes()].concat());
+    let mut cheaper = sealed.clone();
+    cheaper[9..13].copy_from_slice(&1u32.to_le_bytes());
+    assert!(unseal(&cheaper, "pass").unwrap_err().to_string().contains("Wrong passphrase"));
+    let mut greedy = sealed.clone();
+    greedy[5..9].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(unseal(&greedy, "pass").unwrap_err().to_string().contains("KiB"));
+    std::env::set_var(bundle::PASSPHRASE_ENV, "");
+    let unset = EncryptedBundle { path: bundle.path.clone(), passphrase: None };
+    assert!(unset.read().unwrap_err().to_string().contains("empty"));
+    std::env::remove_var(bundle::PASSPHRASE_ENV);
+
+    // Restoring creates the pairings on a freshly installed system, mapped to its adapter.
+    let bluez = temp_dir.path().join("bluetooth");
+    fs::create_dir_all(bluez.join("00:1A:7D:DA:71:13"))?;
+    std::env::set_var("TESTING", "true");
+    let import = SyncOptions { import: true, ..Default::default() };
+    sync_pairings(&bundle, &bluez::BluezTree { path: bluez.clone() }, &import)?;
+    let info = fs::read_to_string(bluez.join("00:1A:7D:DA:71:13").join("FC:51:CA:AC:57:11").join("info"))?;
+    assert_eq!(get_ltk(&info), "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+
+    let args: Vec<String> = ["export", "--bundle", "out.btk"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(cli::parse_args(&args)?, cli::Command::Sync(SyncOptions { export: true, sink: Some(StoreSpec::Bundle("out.btk".into())), ..Default::default() }));
+
+    // Only export reads the running system's own pairings; syncing them into itself is refused.
+    let running = SyncOptions { source: Some(StoreSpec::Bluez(bluez.clone())), ..Default::default() };
+    assert!(store::open_source(bluez.to_str().unwrap(), &running).is_err());
+    assert!(store::open_source(bluez.to_str().unwrap(), &SyncOptions { export: true, ..running.clone() }).is_ok());
+    assert!(store::open_source(temp_dir.path().to_str().unwrap(), &running).is_ok());
+    let args: Vec<String> = ["import", "--bundle", "out.btk", "--sink", "reg:out.reg"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(cli::parse_args(&args)?, cli::Command::Sync(SyncOptions {
+        import: true,
+        source: Some(StoreSpec::Bundle("out.btk".into())),
+        sink: Some(StoreSpec::Reg("out.reg".into())),
+        ..Default::default()
+    }));
+    let args: Vec<String> = ["export"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+    let args: Vec<String> = ["sync", "--bundle", "out.btk"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_macos_plist() -> Result<(), Box<dyn std::error::Error>> {
+    use plist::{Dictionary, Value};
+    use store::PairingSource;
+
+    let dict = |entries: Vec<(&str, Value)>| Value::Dictionary(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect::<Dictionary>());
+    // Keys as macOS stores them, byte-reversed against BlueZ.
+    let link_key: Vec<u8> = (0x00..0x10).rev().collect();
+    let ltk: Vec<u8> = (0x10..0x20).rev().collect();
+    let irk: Vec<u8> = (0x20..0x30).rev().collect();
+    let root = dict(vec![
+        ("LinkKeys", dict(vec![
+            ("74-4c-a1-54-4b-8e", dict(vec![("00-1b-66-00-00-01", Value::Data(link_key))])),
+        ])),
+        ("SMPDistributionKeys", dict(vec![
+            ("74-4c-a1-54-4b-8e", dict(vec![("fc-51-ca-ac-57-11", dict(vec![
+                ("LTK", Value::Data(ltk)),
+                ("IRK", Value::Data(irk)),
+                ("EDIV", Value::Data(vec![0xea, 0x87])),
+                ("RAND", Value::Data(9659891662176722970u64.to_le_bytes().to_vec())),
+                ("AddressType", Value::Integer(1.into())),
+            ])),
+            // Other macOS versions write integers and `Rand`.
+            ("d0-03-df-11-22-33", dict(vec![
+                ("LTK", Value::Data((0x30..0x40).rev().collect())),
+                ("EDIV", Value::Integer(4660.into())),
+                ("Rand", Value::Integer(42.into())),
+                ("KeySize", Value::Integer(7.into())),
+            ]))])),
+        ])),
+    ]);
+
+    let temp_dir = tempdir()?;
+    let plist_path = temp_dir.path().join("macos").join(macos::BLUETOOTHD_PLIST);
+    fs::create_dir_all(plist_path.parent().unwrap())?;
+    root.to_file_binary(&plist_path)?;
+
+    // Pointing at the volume root finds the plist under it.
+    let devices = macos::PlistFile { path: temp_dir.path().join("macos") }.read()?;
+    assert_eq!(devices.len(), 3);
+    let headset = device_named(&devices, "00:1B:66:00:00:01").unwrap();
+    assert_eq!(headset.adapter, "74:4C:A1:54:4B:8E");
+    assert_eq!(headset.link_key, "000102030405060708090A0B0C0D0E0F");
+    let mouse = device_named(&devices, "FC:51:CA:AC:57:11").unwrap();
+    assert_eq!(mouse.ltk, "101112131415161718191A1B1C1D1E1F");
+    assert_eq!(mouse.irk, "202122232425262728292A2B2C2D2E2F");
+    assert_eq!(mouse.ediv, "34794");
+    assert_eq!(mouse.erand, "9659891662176722970");
+    assert_eq!(mouse.address_type, "static");
+    assert_eq!(mouse.enc_size, "16");
+    let keyboard = device_named(&devices, "D0:03:DF:11:22:33").unwrap();
+    assert_eq!((keyboard.ediv.as_str(), keyboard.erand.as_str(), keyboard.enc_size.as_str()), ("4660", "42", "7"));
+    assert_eq!(keyboard.address_type, "public");
+
+    // XML plists parse the same way, and the BlueZ update takes them unchanged.
+    let mut xml = Vec::new();
+    root.to_writer_xml(&mut xml)?;
+    assert_eq!(macos::parse_bluetoothd_plist(&xml)?, macos::parse_bluetoothd_plist(&fs::read(&plist_path)?)?);
+
+    let device = temp_dir.path().join("bluetooth").join("FC:51:CA:AC:57:11");
+    fs::create_dir_all(&device)?;
+    fs::write(device.join("info"), "[General]\nName=Basilisk X HyperSpeed\nAddressType=static\n\n[LongTermKey]\nKey=00000000000000000000000000000000\n")?;
+    std::env::set_var("TESTING", "true");
+    process_bth_device(temp_dir.path().join("bluetooth"), &devices, &SyncOptions::default())?;
+    assert_eq!(get_ltk(&fs::read_to_string(device.join("info"))?), "101112131415161718191A1B1C1D1E1F");
+
+    assert!(macos::parse_bluetoothd_plist(b"not a plist").is_err());
+    let args: Vec<String> = ["--sink", "plist:out.plist"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_android_config() -> Result<(), Box<dyn std::error::Error>> {
+    use android::AndroidConfig;
+    use store::{sync_pairings, PairingSink, PairingSource, StoreSpec};
+
+    let temp_dir = tempdir()?;
+    let data = Path::new(file!()).parent().unwrap().join("data");
+    let options = SyncOptions::default();
+
+    let phone = AndroidConfig { path: data.join("android/bt_config.conf") }.read()?;
+    // Sections without keys are devices the phone only saw.
+    assert_eq!(phone.len(), 3);
+    let headset = device_named(&phone, "Headset").unwrap();
+    assert_eq!(headset.adapter, "74:4C:A1:54:4B:8E");
+    assert_eq!(headset.mac, "00:1B:66:00:00:01");
+    assert_eq!(headset.link_key, "0123456789ABCDEF0123456789ABCDEF");
+    assert_eq!(headset.link_key_type, "5");
+    assert_eq!(headset.local_irk, "514EAE22DDAFF601CFFEE74B2E029AD5");
+    assert_eq!(headset.class, "0x240404");
+    assert_eq!((headset.vendor.as_str(), headset.product.as_str(), headset.version.as_str()), ("94", "4", "512"));
+    assert_eq!(headset.last_written, Some(UNIX_EPOCH + Duration::from_secs(1730572867)));
+    let mouse = device_named(&phone, "Basilisk X HyperSpeed").unwrap();
+    assert_eq!(mouse.ltk, "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+    assert_eq!(mouse.erand, "9659891662176722970");
+    assert_eq!(mouse.ediv, "34794");
+    assert_eq!(mouse.irk, "8EC94951919F694C8DBFD5E0BEA21536");
+    assert_eq!(mouse.address_type, "static");
+    assert_eq!(mouse.enc_size, "16");
+    assert_eq!(mouse.appearance, "0x03c2");
+    assert_eq!(mouse.authenticated, Some(false));
+    let buds = device_named(&phone, "Galaxy Buds").unwrap();
+    assert_eq!(buds.technologies(), vec!["BR/EDR", "LE"]);
+    assert_eq!((buds.link_key_type.as_str(), buds.authenticated), ("8", Some(true)));
+    assert_eq!(buds.pairing_type(), PairingType::SecureConnections);
+
+    // Writing the phone's pairings and reading them back gives the same devices.
+    let fragment = AndroidConfig { path: temp_dir.path().join("fragment.conf") };
+    fragment.write(&phone, &options)?;
+    let round_trip = fragment.read()?;
+    for (key, info) in &phone {
+        assert_eq!(BtDeviceInfo { source: String::new(), ..round_trip[key].clone() }, BtDeviceInfo { source: String::new(), ..info.clone() });
+    }
+
+    // Windows keys come out in the byte order the phone expects, the IRK included.
+    let windows = parse_reg("/dev/test", data.to_str().unwrap())?;
+    fragment.write(&windows, &options)?;
+    let content = fs::read_to_string(&fragment.path)?;
+    assert!(content.contains("[fc:51:ca:ac:57:11]\nName = Basilisk X HyperSpeed\n"));
+    assert!(content.contains("LE_KEY_PENC = d23fedc5f5806af8a37d41d81ee4da5c1a20cd5449d40e86ea870110\n"));
+    assert!(content.contains("LE_KEY_PID = 8ec94951919f694c8dbfd5e0bea2153601fc51caac5711\n"));
+    let from_windows = fragment.read()?;
+    for (key, info) in &windows {
+        let read = &from_windows[key];
+        // Windows records neither the key type nor MITM protection, the file gets the defaults,
+        // and it keeps whole seconds.
+        let last_written = info.last_written.map(|time| UNIX_EPOCH + Duration::from_secs(time.duration_since(UNIX_EPOCH).unwrap().as_secs()));
+        let expected = BtDeviceInfo {
+            source: read.source.clone(),
+            last_written,
+            link_key_type: if info.link_key.is_empty() { String::new() } else { "4".to_string() },
+            authenticated: if info.ltk.is_empty() { None } else { Some(false) },
+            ..info.clone()
+        };
+        assert_eq!(read, &expected);
+    }
+
+    // And from a BlueZ tree, through the same sink, back into a fresh BlueZ tree.
+    let bluez = temp_dir.path().join("bluetooth");
+    let adapter = bluez.join("74:4C:A1:54:4B:8E");
+    for info in phone.values() {
+        fs::create_dir_all(adapter.join(&info.mac))?;
+        fs::write(adapter.join(&info.mac).join("info"), new_bt_info(&info.name, info, PeripheralKeySection::Peripheral))?;
+        fs::File::options().write(true).open(adapter.join(&info.mac).join("info"))?.set_modified(info.last_written.unwrap())?;
+    }
+    fs::write(adapter.join("identity"), update_identity("", &headset.local_irk))?;
+    let linux = bluez::BluezTree { path: bluez.clone() };
+    sync_pairings(&linux, &fragment, &options)?;
+    let from_bluez = fragment.read()?;
+    for (key, info) in &phone {
+        assert_eq!(BtDeviceInfo { source: String::new(), ..from_bluez[key].clone() }, BtDeviceInfo { source: String::new(), ..info.clone() });
+    }
+
+    let restored = temp_dir.path().join("restored");
+    fs::create_dir_all(restored.join("00:1A:7D:DA:71:13"))?;
+    std::env::set_var("TESTING", "true");
+    let import = SyncOptions { import: true, ..Default::default() };
+    sync_pairings(&AndroidConfig { path: data.join("android/bt_config.conf") }, &bluez::BluezTree { path: restored.clone() }, &import)?;
+    let info = fs::read_to_string(restored.join("00:1A:7D:DA:71:13").join("00:1B:66:00:00:01").join("info"))?;
+    assert_eq!(get_link_key(&info), "0123456789ABCDEF0123456789ABCDEF");
+
+    // The file holds one adapter, so pairings from two have to be mapped onto one.
+    let mut two_adapters = phone.clone();
+    two_adapters.values_mut().find(|info| info.name == "Headset").unwrap().adapter = "00:1A:7D:DA:71:13".to_string();
+    assert!(fragment.write(&two_adapters, &options).is_err());
+    let mut mapped = SyncOptions::default();
+    mapped.adapter_map.insert("00:1A:7D:DA:71:13".to_string(), "74:4C:A1:54:4B:8E".to_string());
+    assert!(fragment.write(&two_adapters, &mapped)?);
+
+    assert_eq!(StoreSpec::parse("android:bt_config.conf")?, StoreSpec::Android("bt_config.conf".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_btsnoop_capture() -> Result<(), Box<dyn std::error::Error>> {
+    use store::{PairingSource, StoreSpec};
+
+    // Microseconds from 0000-01-01 to 2024-11-02 18:41:07 UTC.
+    const TIMESTAMP: u64 = 0x00dc_ddb3_0f2f_8000 + 1_730_572_867_000_000;
+    let capture = |datalink: u32, records: &[(u32, Vec<u8>)]| {
+        let mut bytes = b"btsnoop\0".to_vec();
+        bytes.extend(1u32.to_be_bytes());
+        bytes.extend(datalink.to_be_bytes());
+        for (flags, data) in records {
+            bytes.extend((data.len() as u32).to_be_bytes());
+            bytes.extend((data.len() as u32).to_be_bytes());
+            bytes.extend(flags.to_be_bytes());
+            bytes.extend(0u32.to_be_bytes());
+            bytes.extend(TIMESTAMP.to_be_bytes());
+            bytes.extend(data);
+        }
+        bytes
+    };
+    let addr = |mac: &str| -> Vec<u8> { mac.split(':').rev().map(|b| u8::from_str_radix(b, 16).unwrap()).collect() };
+    let key = |hex: &str| -> Vec<u8> { (0..16).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect() };
+    let event = |code: u8, params: Vec<u8>| [vec![0x04, code, params.len() as u8], params].concat();
+    let command = |opcode: u16, params: Vec<u8>| [vec![0x01], opcode.to_le_bytes().to_vec(), vec![params.len() as u8], params].concat();
+    let le_connection = |handle: u16, kind: u8, mac: &str| event(0x3e, [vec![0x01, 0x00], handle.to_le_bytes().to_vec(), vec![0x00, kind], addr(mac), vec![0; 7]].concat());
+    // One SMP PDU, optionally split in two ACL fragments.
+    let smp = |handle: u16, pdu: Vec<u8>, split: usize| -> Vec<Vec<u8>> {
+        let frame = [(pdu.len() as u16).to_le_bytes().to_vec(), 6u16.to_le_bytes().to_vec(), pdu].concat();
+        let (first, rest) = frame.split_at(if split == 0 { frame.len() } else { split });
+        let mut fragments = vec![[vec![0x02], (handle | 0x2000).to_le_bytes().to_vec(), (first.len() as u16).to_le_bytes().to_vec(), first.to_vec()].concat()];
+        if !rest.is_empty() {
+            fragments.push([vec![0x02], (handle | 0x1000).to_le_bytes().to_vec(), (rest.len() as u16).to_le_bytes().to_vec(), rest.to_vec()].concat());
+        }
+        fragments
+    };
+    const SENT: u32 = 0;
+    const RECEIVED: u32 = 1;
+
+    let mut name = b"Headset".to_vec();
+    name.resize(248, 0);
+    let mut records = vec![
+        (RECEIVED, event(0x0e, [vec![0x01], 0x1009u16.to_le_bytes().to_vec(), vec![0x00], addr("74:4C:A1:54:4B:8E")].concat())),
+        (RECEIVED, event(0x18, [addr("00:1B:66:00:00:01"), key("0123456789ABCDEF0123456789ABCDEF"), vec![0x04]].concat())),
+        (RECEIVED, event(0x07, [vec![0x00], addr("00:1B:66:00:00:01"), name].concat())),
+        // A legacy pairing with a device behind a resolvable private address.
+        (RECEIVED, le_connection(0x0040, 0x01, "4A:11:22:33:44:55")),
+    ];
+    let mut pairing = vec![
+        (SENT, vec![0x01, 0x03, 0x00, 0x01, 0x10, 0x07, 0x07]),
+        (RECEIVED, vec![0x02, 0x00, 0x00, 0x01, 0x10, 0x07, 0x07]),
+    ];
+    for (direction, pdu) in pairing.drain(..) {
+        records.extend(smp(0x0040, pdu, 0).into_iter().map(|data| (direction, data)));
+    }
+    // The short-term key the pairing encrypts with first is not a pairing key.
+    records.push((SENT, command(0x2019, [0x0040u16.to_le_bytes().to_vec(), vec![0; 10], key("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF")].concat())));
+    let distribution = vec![
+        (RECEIVED, [vec![0x06], key("D23FEDC5F5806AF8A37D41D81EE4DA5C")].concat(), 9),
+        (RECEIVED, [vec![0x07], 34794u16.to_le_bytes().to_vec(), 9659891662176722970u64.to_le_bytes().to_vec()].concat(), 0),
+        (RECEIVED, [vec![0x08], key("8EC94951919F694C8DBFD5E0BEA21536")].concat(), 0),
+        (RECEIVED, [vec![0x09, 0x01], addr("FC:51:CA:AC:57:11")].concat(), 0),
+        (SENT, [vec![0x06], key("6B2A0F3E9C8D7A6B5C4D3E2F1A0B9C8D")].concat(), 0),
+        (SENT, [vec![0x07], 4660u16.to_le_bytes().to_vec(), 42u64.to_le_bytes().to_vec()].concat(), 0),
+        (SENT, [vec![0x08], key("514EAE22DDAFF601CFFEE74B2E029AD5")].concat(), 0),
+    ];
+    for (direction, pdu, split) in distribution {
+        records.extend(smp(0x0040, pdu, split).into_iter().map(|data| (direction, data)));
+    }
+    records.push((RECEIVED, event(0x05, [vec![0x00], 0x0040u16.to_le_bytes().to_vec(), vec![0x13]].concat())));
+    // A Secure Connections pairing the device starts as central: the LTK only shows up in the
+    // host's reply to the controller's LTK request.
+    records.push((RECEIVED, le_connection(0x0042, 0x00, "D0:03:DF:11:22:33")));
+    for (direction, pdu) in [(RECEIVED, vec![0x01, 0x03, 0x00, 0x09, 0x10, 0x07, 0x07]), (SENT, vec![0x02, 0x00, 0x00, 0x09, 0x10, 0x07, 0x07])] {
+        records.extend(smp(0x0042, pdu, 0).into_iter().map(|data| (direction, data)));
+    }
+    records.push((RECEIVED, event(0x3e, [vec![0x05], 0x0042u16.to_le_bytes().to_vec(), vec![0; 10]].concat())));
+    records.push((SENT, command(0x201a, [0x0042u16.to_le_bytes().to_vec(), key("3C5A9E1F0D2B4C6E8A7F6E5D4C3B2A19")].concat())));
+    records.push((RECEIVED, event(0x05, [vec![0x00], 0x0042u16.to_le_bytes().to_vec(), vec![0x13]].concat())));
+    // A Secure Connections pairing, still connected when the capture ends.
+    records.push((RECEIVED, le_connection(0x0041, 0x00, "AC:8E:BD:24:AC:52")));
+    for (direction, pdu) in [(SENT, vec![0x01, 0x03, 0x00, 0x09, 0x10, 0x07, 0x07]), (RECEIVED, vec![0x02, 0x00, 0x00, 0x09, 0x10, 0x07, 0x07])] {
+        records.extend(smp(0x0041, pdu, 0).into_iter().map(|data| (direction, data)));
+    }
+    records.push((SENT, command(0x2019, [0x0041u16.to_le_bytes().to_vec(), vec![0; 10], key("84417A06F13444B2780E0CC3CF1D353D")].concat())));
+
+    let temp_dir = tempdir()?;
+    let path = temp_dir.path().join("btsnoop_hci.log");
+    fs::write(&path, capture(1002, &records))?;
+    let devices = btsnoop::BtsnoopFile { path: path.clone() }.read()?;
+    assert_eq!(devices.len(), 4);
+
+    let headset = device_named(&devices, "Headset").unwrap();
+    assert_eq!(headset.adapter, "74:4C:A1:54:4B:8E");
+    assert_eq!(headset.mac, "00:1B:66:00:00:01");
+    assert_eq!(headset.link_key, "0123456789ABCDEF0123456789ABCDEF");
+    assert_eq!(headset.link_key_type, "4");
+    assert_eq!(headset.last_written, Some(UNIX_EPOCH + Duration::from_secs(1_730_572_867)));
+
+    let mouse = device_named(&devices, "FC:51:CA:AC:57:11").unwrap();
+    assert_eq!(mouse.address_type, "static");
+    assert_eq!(mouse.ltk, "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+    assert_eq!((mouse.ediv.as_str(), mouse.erand.as_str()), ("34794", "9659891662176722970"));
+    assert_eq!(mouse.irk, "8EC94951919F694C8DBFD5E0BEA21536");
+    assert_eq!(mouse.enc_size, "16");
+    assert_eq!(mouse.peripheral_ltk, "6B2A0F3E9C8D7A6B5C4D3E2F1A0B9C8D");
+    assert_eq!((mouse.peripheral_ediv.as_str(), mouse.peripheral_erand.as_str()), ("4660", "42"));
+    assert_eq!(mouse.local_irk, "514EAE22DDAFF601CFFEE74B2E029AD5");
+
+    let controller = device_named(&devices, "AC:8E:BD:24:AC:52").unwrap();
+    assert_eq!(controller.address_type, "public");
+    assert_eq!(controller.ltk, "84417A06F13444B2780E0CC3CF1D353D");
+    assert_eq!(controller.pairing_type(), PairingType::SecureConnections);
+    let keyboard = device_named(&devices, "D0:03:DF:11:22:33").unwrap();
+    assert_eq!(keyboard.ltk, "3C5A9E1F0D2B4C6E8A7F6E5D4C3B2A19");
+    assert_eq!((keyboard.ediv.as_str(), keyboard.erand.as_str()), ("0", "0"));
+    assert!(keyboard.peripheral_ltk.is_empty());
+
+    // A capture cut off in the middle of a packet keeps what came before it.
+    let bytes = capture(1002, &records);
+    let truncated = btsnoop::parse_btsnoop(&bytes[..bytes.len() - 4])?;
+    assert_eq!(truncated.len(), 3);
+    assert!(device_named(&truncated, "AC:8E:BD:24:AC:52").is_none());
+    assert_eq!(device_named(&truncated, "FC:51:CA:AC:57:11").unwrap().ltk, "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+    assert!(btsnoop::parse_btsnoop(&bytes[..12]).is_err());
+
+    // The keys go through the usual BlueZ update.
+    let device = temp_dir.path().join("bluetooth").join("FC:51:CA:AC:57:11");
+    fs::create_dir_all(&device)?;
+    fs::write(device.join("info"), "[General]\nName=Basilisk X HyperSpeed\nAddressType=static\n\n[LongTermKey]\nKey=00000000000000000000000000000000\n")?;
+    std::env::set_var("TESTING", "true");
+    process_bth_device(temp_dir.path().join("bluetooth"), &devices, &SyncOptions::default())?;
+    let info = fs::read_to_string(device.join("info"))?;
+    assert_eq!(get_ltk(&info), "D23FEDC5F5806AF8A37D41D81EE4DA5C");
+    assert!(info.contains("[PeripheralLongTermKey]\nKey=6B2A0F3E9C8D7A6B5C4D3E2F1A0B9C8D"));
+
+    // btmon -w writes the monitor format: no packet type byte, the adapter comes with New Index.
+    let new_index = [vec![0x00, 0x01], addr("00:1A:7D:DA:71:13"), b"hci1\0\0\0\0".to_vec()].concat();
+    let link_key = [vec![0x18, 23], addr("00:1B:66:00:00:01"), key("00112233445566778899AABBCCDDEEFF"), vec![0x05]].concat();
+    let monitor = btsnoop::parse_btsnoop(&capture(2001, &[(1 << 16, new_index), (1 << 16 | 3, link_key)]))?;
+    assert_eq!(device_named(&monitor, "00:1B:66:00:00:01").unwrap().adapter, "00:1A:7D:DA:71:13");
+    assert_eq!(device_named(&monitor, "00:1B:66:00:00:01").unwrap().link_key, "00112233445566778899AABBCCDDEEFF");
+    assert_eq!(device_named(&monitor, "00:1B:66:00:00:01").unwrap().link_key_type, "5");
+
+    assert!(btsnoop::parse_btsnoop(b"not a capture").is_err());
+    assert!(btsnoop::parse_btsnoop(&capture(1003, &[])).is_err());
+    let args: Vec<String> = ["import", "--btsnoop", "btsnoop_hci.log"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(cli::parse_args(&args)?, cli::Command::Sync(SyncOptions {
+        import: true,
+        source: Some(StoreSpec::Btsnoop("btsnoop_hci.log".into())),
+        ..Default::default()
+    }));
+    let args: Vec<String> = ["--btsnoop", "a.log", "--source", "json:in.json"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+    let args: Vec<String> = ["--sink", "btsnoop:out.log"].iter().map(|s| s.to_string()).collect();
+    assert!(cli::parse_args(&args).is_err());
+
+    Ok(())
+}